@@ -1,11 +1,13 @@
-use std::{iter::Peekable, rc::Rc};
+use std::{fmt, fs, iter::Peekable, path::{Path, PathBuf}, rc::Rc};
 
-use crate::{op::{self, Chunk}, scanner::Scanner, token::{TokenValue, Token}, types::Type, callable::Callable, standard, value::{Value, Object}};
+use crate::{op::{self, Chunk}, scanner::Scanner, token::{TokenValue, Token, TemplatePart}, types::{Type, Bindings, NamedType}, standard, value::{Value, Object}};
 
 #[derive(Debug)]
 pub enum ErrorValue {
     UnexpectedEOF,
+    InvalidNumber(String),
     InvalidTypeAnnotation(String),
+    UnknownType(String),
     BranchTypeMismatch(Type, Type),
     ArgumentTypeMismatch(Type, Type),
     ListItemTypeMismatch(Type, Type),
@@ -13,7 +15,15 @@ pub enum ErrorValue {
     UnexpectedToken(Token),
     RecursiveCall(String),
     InvalidGetTarget(Type),
-    InvalidGetIdentifier(String)
+    InvalidGetIdentifier(String),
+    InvalidComparison(Type, Type),
+    DuplicateBinding(String),
+    NonExhaustiveMatch(Type),
+    MissingDefinition(String),
+    UsedBeforeDefinition(String),
+    ImportFailed(String),
+    ImportCycle(String),
+    InModule(String, Box<Error>),
 }
 #[derive(Debug)]
 pub struct Error {
@@ -30,9 +40,71 @@ impl Error {
     }
 }
 
-pub fn compile<'source>(source: String) -> Result<Function, Error> {
+/// A type that should have satisfied another, with what's different about them
+fn mismatch(f: &mut fmt::Formatter, message: &str, expected: &Type, found: &Type) -> fmt::Result {
+    write!(f, "{}: expected {}, found {}", message, expected, found)?;
+    for line in found.block_diff(expected) {
+        write!(f, "\n    {}", line)?;
+    }
+
+    Ok(())
+}
+
+impl fmt::Display for ErrorValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorValue::UnexpectedEOF => write!(f, "Unexpected end of file"),
+            ErrorValue::InvalidNumber(s) => write!(f, "Invalid number {}", s),
+            ErrorValue::InvalidTypeAnnotation(s) => write!(f, "Invalid type annotation {}", s),
+            ErrorValue::UnknownType(s) => write!(f, "Unknown type {}", s),
+            ErrorValue::BranchTypeMismatch(expected, found) => mismatch(f, "Branch type mismatch", expected, found),
+            ErrorValue::ArgumentTypeMismatch(expected, found) => mismatch(f, "Argument type mismatch", expected, found),
+            ErrorValue::ListItemTypeMismatch(expected, found) => mismatch(f, "List item type mismatch", expected, found),
+            ErrorValue::TypeMismatch(found, expected) => mismatch(f, "Type mismatch", expected, found),
+            ErrorValue::UnexpectedToken(t) => write!(f, "Unexpected token {:?}", t.value),
+            ErrorValue::RecursiveCall(name) => write!(f, "{} needs a full signature to call itself", name),
+            ErrorValue::InvalidGetTarget(t) => write!(f, "Can't get a member of {}", t),
+            ErrorValue::InvalidGetIdentifier(name) => write!(f, "No member named {}", name),
+            ErrorValue::InvalidComparison(left, right) => write!(f, "Can't compare {} with {}", left, right),
+            ErrorValue::DuplicateBinding(name) => write!(f, "{} is already defined", name),
+            ErrorValue::NonExhaustiveMatch(t) => write!(f, "Match doesn't cover every {}", t),
+            ErrorValue::MissingDefinition(name) => write!(f, "{} is declared but never defined", name),
+            ErrorValue::UsedBeforeDefinition(name) => write!(f, "{} is used before it's defined", name),
+            ErrorValue::ImportFailed(path) => write!(f, "Can't import {}", path),
+            ErrorValue::ImportCycle(path) => write!(f, "{} imports itself", path),
+            ErrorValue::InModule(path, e) => write!(f, "In {}: {}", path, e),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Line {}: {}", self.line, self.value)
+    }
+}
+
+type Params = Vec<(Pattern, Type)>;
+
+pub fn compile(source: String) -> Result<Function, Error> {
     // Construct the compiler
-    let mut compiler = Compiler::new(source);
+    let mut compiler = Compiler::new(source, PathBuf::new());
+
+    compiler.compile()
+}
+
+/// Compile a file, with its imports relative to it
+pub fn compile_file(path: &Path) -> Result<Function, Error> {
+    let path = match fs::canonicalize(path) {
+        Ok(p) => p,
+        Err(_) => return Err(Error::new(ErrorValue::ImportFailed(path.display().to_string()), 0)),
+    };
+    let source = match fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(_) => return Err(Error::new(ErrorValue::ImportFailed(path.display().to_string()), 0)),
+    };
+
+    let mut compiler = Compiler::new(source, path.clone());
+    compiler.importing.push(path);
 
     compiler.compile()
 }
@@ -43,6 +115,8 @@ pub struct Local {
     index: usize,
     depth: usize,
     type_: Type,
+    cell: bool,
+    defined: bool,
 }
 
 
@@ -53,6 +127,8 @@ impl Clone for Local {
             index: self.index,
             depth: self.depth,
             type_: self.type_.clone(),
+            cell: self.cell,
+            defined: self.defined,
         }
     }
 }
@@ -78,6 +154,32 @@ impl Clone for Global {
     }
 }
 
+#[derive(Debug, Clone)]
+enum Pattern {
+    Wildcard,
+    Binding(String),
+    Literal(Value, Type),
+    Array(Vec<Pattern>, Option<Box<Pattern>>),
+    Block(Vec<(String, Pattern)>),
+    Variant(String, Box<Pattern>),
+}
+
+/// A step from a matched value to one of its parts
+#[derive(Debug, Clone)]
+enum Access {
+    Index(i64),
+    Member(String),
+    Rest(i64),
+    Payload,
+}
+
+/// A compiled file and the type of the block it evaluates to
+struct Module {
+    path: PathBuf,
+    function: Rc<Function>,
+    type_: Type,
+}
+
 #[derive(Debug)]
 pub struct Function {
     pub name: String,
@@ -85,6 +187,7 @@ pub struct Function {
     pub locals: Vec<Local>,
     pub upvalues: Vec<Upvalue>,
     pub depth: usize,
+    pub recursive: bool,
 }
 
 impl Function {
@@ -95,6 +198,7 @@ impl Function {
             locals: Vec::new(),
             upvalues: Vec::new(),
             depth: 0,
+            recursive: false,
         }
     }
 
@@ -106,11 +210,23 @@ impl Function {
             index,
             depth: self.depth,
             type_,
+            cell: false,
+            defined: true,
         });
 
         index
     }
 
+    fn declare_local(&mut self, name: String, type_: Type) -> usize {
+        let index = self.add_local(name, type_);
+
+        // Declared locals hold a cell that is filled in by the definition
+        self.locals[index].cell = true;
+        self.locals[index].defined = false;
+
+        index
+    }
+
     fn add_upvalue(&mut self, index: usize, is_local: bool) -> usize {
         for (i, upvalue) in self.upvalues.iter().enumerate() {
             if upvalue.index == index && upvalue.is_local == is_local {
@@ -145,18 +261,7 @@ impl Function {
     }
 
     fn resolve(&self, name: &str) -> Option<&Local> {
-        for (_i, local) in self.locals.iter().enumerate() {
-            if local.name == name {
-                return Some(local);
-            }
-        }
-        return None;
-    }
-}
-
-impl Callable for Function {
-    fn call(&self, vm: &mut crate::vm::VM) -> Result<(), crate::vm::Error> {
-        vm.run(self, Vec::new())
+        self.locals.iter().find(|local| local.name == name)
     }
 }
 
@@ -166,20 +271,54 @@ struct Compiler {
     globals: Vec<Global>,
     last_type: Type,
     unresolved_types: Vec<String>,
+    // Types declared with a name
+    types: Vec<(String, Type)>,
+    // Type variables of the generic functions being compiled
+    generics: Vec<String>,
+    // Set while compiling call arguments, where a pipe ends the call instead
+    arguments: bool,
+    // The local holding a piped value, until a call takes it as its first argument
+    piped: Option<usize>,
+    // The function type a call expects for the lambda being passed to it
+    expected: Option<Type>,
+    // Set while compiling an argument where a function is expected, so a named function is passed instead of called
+    passing: bool,
+    // Optional locals known to have a value, by function and local index
+    narrowed: Vec<((usize, usize), Type)>,
+    // The name last read on its own, and where the code for it ends
+    read: Option<(String, (usize, usize))>,
+    // The name last compared with none, whether it has a value when the comparison holds, and where the comparison ends
+    compared: Option<(String, bool, (usize, usize))>,
+    // The file being compiled, the files imported so far, and the chain of files being imported
+    path: PathBuf,
+    modules: Vec<Module>,
+    importing: Vec<PathBuf>,
     line: usize
 }
 
 impl Compiler {
-    fn new(source: String) -> Compiler {
+    fn new(source: String, path: PathBuf) -> Compiler {
         Compiler {
             tokens: Scanner::new(source).peekable(),
             functions: Vec::new(),
             globals: standard::get_functions().iter().map(|f| Global {
                 name: f.get_name().to_string(),
-                type_: f.get_type(),
+                type_: f.get_type().generalize(&f.get_type().vars()),
             }).collect(),
             last_type: Type::Nil,
             unresolved_types: Vec::new(),
+            types: Vec::new(),
+            generics: Vec::new(),
+            arguments: false,
+            piped: None,
+            expected: None,
+            passing: false,
+            narrowed: Vec::new(),
+            read: None,
+            compared: None,
+            path,
+            modules: Vec::new(),
+            importing: Vec::new(),
             line: 1
         }
     }
@@ -188,6 +327,11 @@ impl Compiler {
         self.functions.last_mut().unwrap()
     }
 
+    /// Where the code being written is up to, by function and offset
+    fn position(&mut self) -> (usize, usize) {
+        (self.functions.len(), self.get_function().chunk.code.len())
+    }
+
     fn ignore_whitespace(&mut self) {
         while self.take(TokenValue::Newline).is_some() { }
     }
@@ -232,6 +376,14 @@ impl Compiler {
         Error::new(value, self.line)
     }
 
+    fn expect(&mut self, value: TokenValue) -> Result<Token, Error> {
+        match self.next() {
+            Some(t) if t.value == value => Ok(t),
+            Some(t) => Err(self.error(ErrorValue::UnexpectedToken(t))),
+            None => Err(self.error(ErrorValue::UnexpectedEOF)),
+        }
+    }
+
     fn assert_type(&mut self, expected: Type) -> Result<(), Error> {
         if self.last_type == Type::Unknown {
             let n = self.unresolved_types.pop().unwrap();
@@ -250,6 +402,42 @@ impl Compiler {
         Ok(())
     }
 
+    /// Make sure the last value can be compared for equality with a value of the given type
+    fn assert_comparable(&mut self, left: Type) -> Result<(), Error> {
+        let right = self.last_type.clone();
+
+        if !left.is_comparable() || !right.is_comparable() {
+            return Err(self.error(ErrorValue::InvalidComparison(left, right)));
+        }
+
+        match (left.resolved(), right.resolved()) {
+            (Type::Unknown | Type::Any, _) | (_, Type::Any) => Ok(()),
+            (_, Type::Unknown) => self.assert_type(left),
+            (Type::Number | Type::Float, Type::Number | Type::Float) => Ok(()),
+            _ if left.satisfies(right.clone()) || right.satisfies(left.clone()) => Ok(()),
+            _ => Err(self.error(ErrorValue::InvalidComparison(left, right))),
+        }
+    }
+
+    /// Numeric operands are integers unless they are known to be floats
+    fn assert_numeric(&mut self) -> Result<Type, Error> {
+        if *self.last_type.resolved() == Type::Float {
+            Ok(Type::Float)
+        } else {
+            self.assert_type(Type::Number)?;
+            Ok(Type::Number)
+        }
+    }
+
+    /// Integers are promoted to floats when mixed with them
+    fn arithmetic_type(left: Type, right: Type) -> Type {
+        if left == Type::Float || right == Type::Float {
+            Type::Float
+        } else {
+            Type::Number
+        }
+    }
+
     fn assert_type_with_error(&mut self, expected: Type, error: Error) -> Result<(), Error> {
         if let Ok(()) = self.assert_type(expected.clone()) {
             Ok(())
@@ -263,22 +451,52 @@ impl Compiler {
 
         while self.peek().is_some() {
             self.ignore_whitespace();
-            self.expression(false)?;
+            self.statement(false)?;
             self.ignore_whitespace();
         }
 
+        self.assert_defined()?;
+
         Ok(self.functions.pop().unwrap())
     }
 
+    /// Every declaration in the current scope needs a definition
+    fn assert_defined(&mut self) -> Result<(), Error> {
+        let depth = self.get_function().depth;
+        if let Some(local) = self.get_function().locals.iter().find(|local| local.depth >= depth && !local.defined) {
+            let name = local.name.clone();
+            return Err(self.error(ErrorValue::MissingDefinition(name)));
+        }
+
+        Ok(())
+    }
+
+    /// An expression on its own, which can also define a function declared earlier in the same scope
+    fn statement(&mut self, keep: bool) -> Result<(), Error> {
+        if let Some(TokenValue::Identifier(name)) = self.peek().map(|t| t.value.clone()) {
+            if let Some(local) = self.get_function().resolve(&name).cloned() {
+                if !local.defined {
+                    self.next();
+                    return self.definition(local);
+                }
+            }
+        }
+
+        self.expression(keep)
+    }
+
     fn expression(&mut self, keep: bool) -> Result<(), Error> {
-        self.or()?;
+        self.coalesce()?;
 
         // Automatically call functions
-        match &self.last_type {
-            Type::Function(_params, _return_type) => {
-                self.execute_call()?;
-            },
-            _ => {}
+        if let Type::Function(_params, _return_type) = self.last_type.resolved() {
+            self.execute_call()?;
+        }
+
+        if !self.arguments {
+            while self.take(TokenValue::Pipe).is_some() {
+                self.pipe()?;
+            }
         }
 
         if !keep && self.last_type != Type::Nil {
@@ -288,6 +506,63 @@ impl Compiler {
         Ok(())
     }
 
+    /// Pass the last value as the first argument of the call that follows
+    fn pipe(&mut self) -> Result<(), Error> {
+        let left = self.last_type.clone();
+        if left == Type::Nil {
+            return Err(self.error(ErrorValue::TypeMismatch(Type::Nil, Type::Any)));
+        }
+
+        self.get_function().begin_scope();
+        self.get_function().chunk.write(op::PUSH_LOCAL);
+        let index = self.get_function().add_local(String::new(), left.clone());
+        let outer = self.piped.replace(index);
+
+        self.or()?;
+        if let Type::Function(_params, _return_type) = self.last_type.resolved() {
+            self.execute_call()?;
+        }
+
+        // Nothing took the value
+        if self.piped.is_some() {
+            let expected = Type::Function(vec![left], Box::new(Type::Any));
+            return Err(self.error(ErrorValue::TypeMismatch(self.last_type.clone(), expected)));
+        }
+
+        self.piped = outer;
+        self.get_function().end_scope();
+
+        Ok(())
+    }
+
+    /// Use the value on the right when the optional value on the left is missing
+    fn coalesce(&mut self) -> Result<(), Error> {
+        self.or()?;
+        while self.take(TokenValue::QuestionQuestion).is_some() {
+            let inner = match self.last_type.resolved().clone() {
+                Type::Optional(t) => *t,
+                t => return Err(self.error(ErrorValue::TypeMismatch(t, Type::Optional(Box::new(Type::Any))))),
+            };
+
+            let jump = self.get_function().chunk.code.len();
+            self.get_function().chunk.write_pair(op::JUMP_IF_PRESENT, 0);
+
+            self.or()?;
+
+            // Chained defaults can be optional too
+            if let Type::Optional(t) = self.last_type.resolved().clone() {
+                self.last_type = *t;
+                self.assert_type(inner.clone())?;
+                self.last_type = inner.optional();
+            } else {
+                self.assert_type(inner)?;
+            }
+
+            self.patch_jump(jump);
+        }
+        Ok(())
+    }
+
     fn or(&mut self) -> Result<(), Error> {
         self.and()?;
         while self.take(TokenValue::Or).is_some() {
@@ -313,23 +588,40 @@ impl Compiler {
     fn equality(&mut self) -> Result<(), Error> {
         self.comparison()?;
         while let Some(t) = self.take_any(vec![TokenValue::BangEqual, TokenValue::EqualEqual]) {
+            let left = self.last_type.clone();
+            let position = self.position();
+            let read = self.read.take().filter(|(_, end)| *end == position);
+            let none = self.has(TokenValue::None);
             self.comparison()?;
+            self.assert_comparable(left)?;
+
             self.last_type = Type::Boolean;
             match t.value {
                 TokenValue::BangEqual => self.get_function().chunk.write(op::NOT_EQUAL),
                 TokenValue::EqualEqual => self.get_function().chunk.write(op::EQUAL),
                 _ => unreachable!(),
             }
+
+            // Comparing a name with none tells whether it has a value, as long as nothing else is done with the result
+            if let (Some((name, _)), true) = (read, none) {
+                self.compared = Some((name, t.value == TokenValue::BangEqual, self.position()));
+            }
         }
         Ok(())
     }
 
     fn comparison(&mut self) -> Result<(), Error> {
-        self.addition()?;
+        self.range()?;
         while let Some(t) = self.take_any(vec![TokenValue::GreaterThan, TokenValue::GreaterThanEqual, TokenValue::LessThan, TokenValue::LessThanEqual]) {
-            self.assert_type(Type::Number)?;
-            self.addition()?;
-            self.assert_type(Type::Number)?;
+            if *self.last_type.resolved() == Type::String {
+                // Strings are ordered lexicographically
+                self.range()?;
+                self.assert_type(Type::String)?;
+            } else {
+                self.assert_numeric()?;
+                self.range()?;
+                self.assert_numeric()?;
+            }
 
             self.last_type = Type::Boolean;
             match t.value {
@@ -343,12 +635,50 @@ impl Compiler {
         Ok(())
     }
 
+    fn range(&mut self) -> Result<(), Error> {
+        self.concatenation()?;
+        if let Some(t) = self.take_any(vec![TokenValue::DotDot, TokenValue::DotDotEqual]) {
+            self.assert_type(Type::Number)?;
+            self.concatenation()?;
+            self.assert_type(Type::Number)?;
+
+            // Count up by one unless there's a step
+            if self.take(TokenValue::Identifier("by".to_string())).is_some() {
+                self.concatenation()?;
+                self.assert_type(Type::Number)?;
+            } else {
+                let constant = self.get_function().chunk.add_constant(Value::Number(1));
+                self.get_function().chunk.write_pair(op::PUSH, constant);
+            }
+
+            let inclusive = t.value == TokenValue::DotDotEqual;
+            self.get_function().chunk.write_pair(op::RANGE, inclusive as u16);
+            self.last_type = Type::Array(Box::new(Type::Number));
+        }
+        Ok(())
+    }
+
+    fn concatenation(&mut self) -> Result<(), Error> {
+        self.addition()?;
+        while self.take(TokenValue::PlusPlus).is_some() {
+            let mut item_type = Type::Never;
+            self.spread_items(&mut item_type)?;
+            self.addition()?;
+            self.spread_items(&mut item_type)?;
+
+            self.get_function().chunk.write_pair(op::MERGE, 2);
+            self.last_type = Type::Array(Box::new(item_type));
+        }
+        Ok(())
+    }
+
     fn addition(&mut self) -> Result<(), Error> {
         self.multiplication()?;
         while let Some(t) = self.take_any(vec![TokenValue::Plus, TokenValue::Minus]) {
-            self.assert_type(Type::Number)?;
+            let left = self.assert_numeric()?;
             self.multiplication()?;
-            self.assert_type(Type::Number)?;
+            let right = self.assert_numeric()?;
+            self.last_type = Self::arithmetic_type(left, right);
 
             match t.value {
                 TokenValue::Plus => self.get_function().chunk.write(op::ADD),
@@ -361,13 +691,15 @@ impl Compiler {
 
     fn multiplication(&mut self) -> Result<(), Error> {
         self.unary()?;
-        while let Some(t) = self.take_any(vec![TokenValue::Star, TokenValue::Slash]) {
-            self.assert_type(Type::Number)?;
+        while let Some(t) = self.take_any(vec![TokenValue::Star, TokenValue::Slash, TokenValue::Percent]) {
+            let left = self.assert_numeric()?;
             self.unary()?;
-            self.assert_type(Type::Number)?;
+            let right = self.assert_numeric()?;
+            self.last_type = Self::arithmetic_type(left, right);
             match t.value {
                 TokenValue::Star => self.get_function().chunk.write(op::MULTIPLY),
                 TokenValue::Slash => self.get_function().chunk.write(op::DIVIDE),
+                TokenValue::Percent => self.get_function().chunk.write(op::MODULO),
                 _ => unreachable!(),
             }
         }
@@ -376,31 +708,44 @@ impl Compiler {
 
     fn unary(&mut self) -> Result<(), Error> {
         if let Some(t) = self.take_any(vec![TokenValue::Bang, TokenValue::Minus]) {
-            self.get()?;
+            self.power()?;
             match t.value {
                 TokenValue::Bang => {
                     self.assert_type(Type::Boolean)?;
                     self.get_function().chunk.write(op::NOT)
                 },
                 TokenValue::Minus => {
-                    self.assert_type(Type::Number)?;
+                    self.assert_numeric()?;
                     self.get_function().chunk.write(op::NEGATE)
                 },
                 _ => unreachable!(),
             }
         } else {
-            self.get()?;
+            self.power()?;
         }
 
         Ok(())
     }
 
+    fn power(&mut self) -> Result<(), Error> {
+        self.get()?;
+        if self.take(TokenValue::StarStar).is_some() {
+            // Right associative, and binds tighter than a negation on the left
+            let left = self.assert_numeric()?;
+            self.unary()?;
+            let right = self.assert_numeric()?;
+            self.last_type = Self::arithmetic_type(left, right);
+            self.get_function().chunk.write(op::POWER);
+        }
+        Ok(())
+    }
+
     fn get(&mut self) -> Result<(), Error> {
         self.primary()?;
 
         // TODO: find a cleaner way to do this
         'outer: while self.take(TokenValue::Dot).is_some() {
-            let obj_type = self.last_type.clone();
+            let obj_type = self.last_type.resolved().clone();
 
             match obj_type {
                 Type::Array(list_type) => {
@@ -415,21 +760,18 @@ impl Compiler {
                     if let Some(t) = self.next() {
                         if let TokenValue::Identifier(name) = t.value {
                             // Check if it's a local
-                            for (i, (n, t)) in block.iter().enumerate() {
+                            for (n, t) in block.iter() {
                                 if n.eq(&name) {
-                                    // Add numeric constant
-                                    let i = self.get_function().chunk.add_constant(Value::Number(i as i32));
+                                    // Members are looked up by name since a block may have more than its type says
+                                    let i = self.get_function().chunk.add_constant(Value::Object(Object::String(name)));
                                     self.get_function().chunk.write_pair(op::PUSH, i);
                                     self.get_function().chunk.write(op::INDEX);
 
                                     self.last_type = t.clone();
 
                                     // Execute call for functions
-                                    match t {
-                                        Type::Function(_, _) => {
-                                            self.execute_call()?;
-                                        },
-                                        _ => {}
+                                    if let Type::Function(_, _) = t.resolved() {
+                                        self.execute_call()?;
                                     }
 
                                     continue 'outer;
@@ -444,12 +786,21 @@ impl Compiler {
                         return Err(self.error(ErrorValue::UnexpectedEOF));
                     }
                 },
-                t => {
-                    if t == Type::Unknown {
-                        continue;
+                Type::Never => {
+                    // There's never a value to get a member of, so any member will do
+                    if let Some(TokenValue::Identifier(name)) = self.peek().map(|t| t.value.clone()) {
+                        self.next();
+                        let i = self.get_function().chunk.add_constant(Value::Object(Object::String(name)));
+                        self.get_function().chunk.write_pair(op::PUSH, i);
+                    } else {
+                        self.primary()?;
                     }
-                    return Err(self.error(ErrorValue::InvalidGetTarget(t)));
-                }
+                    self.get_function().chunk.write(op::INDEX);
+
+                    self.last_type = Type::Never;
+                },
+                Type::Unknown => continue,
+                _ => return Err(self.error(ErrorValue::InvalidGetTarget(self.last_type.clone()))),
             }
         }
 
@@ -460,22 +811,40 @@ impl Compiler {
         if let Some(t) = self.next() {
             return match t.value {
                 TokenValue::LeftParen => self.group(),
+                TokenValue::LeftBracket | TokenValue::LeftBrace if self.destructuring_ahead() => self.destructuring(t),
                 TokenValue::LeftBracket => self.list(),
                 TokenValue::LeftBrace => self.block(),
                 TokenValue::String(s) => {
                     let constant = self.get_function().chunk.add_constant(Value::Object(Object::String(s)));
                     self.last_type = Type::String;
-                    Ok(self.get_function().chunk.write_pair(op::PUSH, constant))
+                    self.get_function().chunk.write_pair(op::PUSH, constant);
+                    Ok(())
                 },
+                TokenValue::Template(parts) => self.template(parts),
                 TokenValue::Number(n) => {
                     let constant = self.get_function().chunk.add_constant(Value::Number(n));
                     self.last_type = Type::Number;
-                    Ok(self.get_function().chunk.write_pair(op::PUSH, constant))
+                    self.get_function().chunk.write_pair(op::PUSH, constant);
+                    Ok(())
+                },
+                TokenValue::InvalidNumber(s) => Err(self.error(ErrorValue::InvalidNumber(s))),
+                TokenValue::Float(n) => {
+                    let constant = self.get_function().chunk.add_constant(Value::Float(n));
+                    self.last_type = Type::Float;
+                    self.get_function().chunk.write_pair(op::PUSH, constant);
+                    Ok(())
                 },
                 TokenValue::Boolean(b) => {
                     let constant = self.get_function().chunk.add_constant(Value::Boolean(b));
                     self.last_type = Type::Boolean;
-                    Ok(self.get_function().chunk.write_pair(op::PUSH, constant))
+                    self.get_function().chunk.write_pair(op::PUSH, constant);
+                    Ok(())
+                },
+                TokenValue::None => {
+                    let constant = self.get_function().chunk.add_constant(Value::Nil);
+                    self.last_type = Type::Optional(Box::new(Type::Any));
+                    self.get_function().chunk.write_pair(op::PUSH, constant);
+                    Ok(())
                 },
                 TokenValue::Identifier(s) => self.call(s),
                 TokenValue::BackSlash => self.function(String::new()),
@@ -485,27 +854,54 @@ impl Compiler {
                 TokenValue::For => {
                     self.for_()
                 },
+                TokenValue::Match => {
+                    self.match_()
+                },
+                TokenValue::Import => {
+                    self.import()
+                },
+                TokenValue::Type => {
+                    self.type_declaration()
+                },
                 _ => {
                     Err(self.error(ErrorValue::UnexpectedToken(t)))
                 }
             };
         }
 
-        return Err(self.error(ErrorValue::UnexpectedEOF));
+        Err(self.error(ErrorValue::UnexpectedEOF))
     }
 
     fn if_(&mut self, type_: Option<Type>) -> Result<(), Error> {   
         let mut type_ = type_;
 
         // Already consumed the if
+        self.compared = None;
         self.expression(true)?;
 
+        // Comparing an optional local with none tells which branch it has a value in
+        let position = self.position();
+        let narrowing = self.compared.take().filter(|(_, _, end)| *end == position).map(|(name, then, _)| (name, then));
+        let mut narrowed = (None, None);
+        if let Some((name, then)) = narrowing {
+            if let Some((i, local)) = self.find_local(&name) {
+                if let Type::Optional(t) = self.local_type(i, &local).resolved().clone() {
+                    let n = ((i, local.index), *t);
+                    if then {
+                        narrowed.0 = Some(n);
+                    } else {
+                        narrowed.1 = Some(n);
+                    }
+                }
+            }
+        }
+
         // Emit initial jump
         let then_jump = self.get_function().chunk.code.len();
         self.get_function().chunk.write_pair(op::JUMP_IF_FALSE, 0);
 
         // Then block
-        self.expression(true)?;
+        self.narrow(narrowed.0.clone(), |c| c.expression(true))?;
 
         if let Some(t) = &type_ {
             // Assert type
@@ -521,13 +917,11 @@ impl Compiler {
         self.get_function().chunk.write_pair(op::JUMP, 0);
 
         // Patch the initial jump
-        let index_bytes = u16::to_be_bytes((self.get_function().chunk.code.len() - then_jump) as u16 - 1);
-        self.get_function().chunk.code[then_jump + 1] = index_bytes[0];
-        self.get_function().chunk.code[then_jump + 2] = index_bytes[1];
+        self.patch_jump(then_jump);
 
         // Handle optional else
         if self.take(TokenValue::Else).is_some() {
-            self.expression(true)?;
+            self.narrow(narrowed.1.clone(), |c| c.expression(true))?;
             
             if let Some(t) = type_.clone() {
                 // Assert type
@@ -537,116 +931,956 @@ impl Compiler {
         }
 
         // Patch the else jump
-        let index_bytes = u16::to_be_bytes((self.get_function().chunk.code.len() - else_jump) as u16 - 1);
-        self.get_function().chunk.code[else_jump + 1] = index_bytes[0];
-        self.get_function().chunk.code[else_jump + 2] = index_bytes[1];
+        self.patch_jump(else_jump);
 
         Ok(())
     }
 
-    fn for_(&mut self) -> Result<(), Error> {
-        // TODO: Compile body as a function and call it with a "Loop" opcode
-        todo!("implement for")
-    }
+    /// Compile something with a local's type narrowed
+    fn narrow(&mut self, narrowed: Option<((usize, usize), Type)>, f: impl FnOnce(&mut Self) -> Result<(), Error>) -> Result<(), Error> {
+        let n = self.narrowed.len();
+        if let Some(narrowed) = narrowed {
+            self.narrowed.push(narrowed);
+        }
 
-    fn resolve_upvalue(&mut self, name: &str) -> bool {
-        // Resolve, flagging upvalues top to bottom
-        for i in (0..(self.functions.len() - 1)).rev() {
-            if let Some(local) = &self.functions[i].resolve(name).cloned() {
-                let is_local = i >= self.functions.len() - 2;
-                let upvalue_index = self.get_function().add_upvalue(local.index, is_local);
-                self.last_type = local.type_.clone();
-                self.get_function().chunk.write_pair(op::GET_UPVALUE, upvalue_index as u16);
+        let result = f(self);
+        self.narrowed.truncate(n);
 
-                // Now we need to need to add that upvalue to all the intermediate ones
-                let mut is_local = true;
-                let mut index = local.index;
-                for j in (i + 1)..self.functions.len() - 1 {
-                    index = self.functions[j].add_upvalue(index, is_local);
-                    is_local = false;
-                }
+        result
+    }
 
-                return true
-            }
+    /// Find the innermost function with a local, and the local
+    fn find_local(&self, name: &str) -> Option<(usize, Local)> {
+        (0..self.functions.len()).rev().find_map(|i| self.functions[i].resolve(name).map(|local| (i, local.clone())))
+    }
+
+    /// The type of a local, taking narrowing into account
+    fn local_type(&self, function: usize, local: &Local) -> Type {
+        match self.narrowed.iter().rev().find(|(key, _)| *key == (function, local.index)) {
+            Some((_, t)) => t.clone(),
+            None => local.type_.clone(),
         }
+    }
 
-        false
+    /// Point the jump at the given position to the current end of the chunk
+    fn patch_jump(&mut self, jump: usize) {
+        let index_bytes = u16::to_be_bytes((self.get_function().chunk.code.len() - jump) as u16 - 1);
+        self.get_function().chunk.code[jump + 1] = index_bytes[0];
+        self.get_function().chunk.code[jump + 2] = index_bytes[1];
     }
 
-    fn resolve_local(&mut self, name: &str) -> bool {
-        if let Some(local) = self.get_function().resolve(name).cloned() {
-            self.last_type = local.type_.clone();
+    fn match_(&mut self) -> Result<(), Error> {
+        // Already consumed the match
+        self.expression(true)?;
+        let subject_type = self.last_type.clone();
+        if subject_type == Type::Nil {
+            return Err(self.error(ErrorValue::TypeMismatch(Type::Nil, Type::Any)));
+        }
 
-            self.unresolved_types.push(name.to_string());
-            self.get_function().chunk.write_pair(op::GET_LOCAL, local.index as u16);
-            return true;
+        // Keep the subject in an unnamed local while the patterns are tested
+        self.get_function().begin_scope();
+        self.get_function().chunk.write(op::PUSH_LOCAL);
+        let subject = self.get_function().add_local(String::new(), subject_type.clone());
+
+        self.expect(TokenValue::LeftParen)?;
+        self.ignore_whitespace();
+
+        let mut type_: Option<Type> = None;
+        let mut end_jumps = Vec::new();
+        let mut booleans = (false, false);
+        let mut variants = Vec::new();
+        let mut exhaustive = false;
+
+        while !self.has(TokenValue::RightParen) {
+            let pattern = self.pattern()?;
+            self.expect(TokenValue::FatArrow)?;
+
+            match &pattern {
+                Pattern::Wildcard | Pattern::Binding(_) => exhaustive = true,
+                Pattern::Literal(Value::Boolean(true), _) => booleans.0 = true,
+                Pattern::Literal(Value::Boolean(false), _) => booleans.1 = true,
+                Pattern::Variant(name, payload) if Self::irrefutable(payload) => variants.push(name.clone()),
+                _ => {},
+            }
+
+            // Test the whole pattern before binding anything
+            let mut fail_jumps = Vec::new();
+            self.test_pattern(&pattern, subject, &mut Vec::new(), subject_type.clone(), &mut fail_jumps)?;
+
+            self.get_function().begin_scope();
+            self.bind_pattern(&pattern, subject, &mut Vec::new(), subject_type.clone())?;
+
+            self.ignore_whitespace();
+            self.expression(true)?;
+
+            if let Some(t) = &type_ {
+                // Assert type
+                let error = self.error(ErrorValue::BranchTypeMismatch(t.clone(), self.last_type.clone()));
+                self.assert_type_with_error(t.clone(), error)?;
+            } else {
+                // Set type
+                type_ = Some(self.last_type.clone());
+            }
+
+            self.get_function().end_scope();
+
+            end_jumps.push(self.get_function().chunk.code.len());
+            self.get_function().chunk.write_pair(op::JUMP, 0);
+
+            for jump in fail_jumps {
+                self.patch_jump(jump);
+            }
+
+            self.ignore_whitespace();
         }
 
-        false
-    }
+        self.expect(TokenValue::RightParen)?;
 
-    fn resolve_global(&mut self, name: &str) -> bool {
-        let globals = self.globals.clone();
-        for (i, g) in globals.iter().clone().enumerate() {
-            if g.name == name {
-                self.get_function().chunk.write_pair(op::GET_GLOBAL, i as u16);
-                self.last_type = g.type_.clone();
-                return true;
+        if *subject_type.resolved() == Type::Boolean && !exhaustive && !(booleans.0 && booleans.1) {
+            return Err(self.error(ErrorValue::NonExhaustiveMatch(subject_type)));
+        }
+
+        // Every variant of a union needs a branch
+        if let Type::Union(_, all) = subject_type.resolved() {
+            if !exhaustive && !all.iter().all(|(name, _)| variants.contains(name)) {
+                return Err(self.error(ErrorValue::NonExhaustiveMatch(subject_type)));
             }
         }
 
-       false
+        // Nothing matched
+        self.get_function().chunk.write_pair(op::GET_LOCAL, subject as u16);
+        self.get_function().chunk.write(op::NO_MATCH);
+
+        for jump in end_jumps {
+            self.patch_jump(jump);
+        }
+
+        self.get_function().end_scope();
+
+        self.last_type = type_.unwrap_or(Type::Nil);
+
+        Ok(())
     }
 
-    fn call(&mut self, name: String) -> Result<(), Error> {
-        if self.resolve_local(&name) || self.resolve_upvalue(&name) || self.resolve_global(&name){
-            self.execute_call()
-        } else if self.get_function().name == name {
-            Err(self.error(ErrorValue::RecursiveCall(name)))
-        } else {
-            self.assignment(name)
+    fn pattern(&mut self) -> Result<Pattern, Error> {
+        self.ignore_whitespace();
+        match self.next() {
+            Some(t) => self.pattern_from(t),
+            None => Err(self.error(ErrorValue::UnexpectedEOF)),
         }
     }
 
-    fn execute_call(&mut self) -> Result<(), Error> {
-        match self.last_type.clone() {
-            Type::Function(params, return_type) => {
-                if self.has(TokenValue::Newline) | self.has(TokenValue::RightParen) | self.peek().is_none() {
-                    // Not a call, just passing a function around
-                    return Ok(())
+    fn pattern_from(&mut self, t: Token) -> Result<Pattern, Error> {
+        match t.value {
+            TokenValue::Identifier(name) if name == "_" => Ok(Pattern::Wildcard),
+            TokenValue::Identifier(name) => match self.variant(&name) {
+                Some((_, payload)) => {
+                    // A variant with a payload can be followed by a pattern for it
+                    let takes_payload = payload != Type::Block(Vec::new())
+                        && matches!(self.peek(), Some(Token { value: TokenValue::Identifier(_) | TokenValue::LeftBrace, .. }));
+                    let payload = if takes_payload { self.pattern()? } else { Pattern::Wildcard };
+
+                    Ok(Pattern::Variant(name, Box::new(payload)))
+                },
+                None => Ok(Pattern::Binding(name)),
+            },
+            TokenValue::Number(n) => Ok(Pattern::Literal(Value::Number(n), Type::Number)),
+            TokenValue::Float(n) => Ok(Pattern::Literal(Value::Float(n), Type::Float)),
+            TokenValue::String(s) => Ok(Pattern::Literal(Value::Object(Object::String(s)), Type::String)),
+            TokenValue::Boolean(b) => Ok(Pattern::Literal(Value::Boolean(b), Type::Boolean)),
+            TokenValue::None => Ok(Pattern::Literal(Value::Nil, Type::Optional(Box::new(Type::Any)))),
+            TokenValue::Minus => match self.next() {
+                Some(Token { value: TokenValue::Number(n), .. }) => Ok(Pattern::Literal(Value::Number(-n), Type::Number)),
+                Some(Token { value: TokenValue::Float(n), .. }) => Ok(Pattern::Literal(Value::Float(-n), Type::Float)),
+                Some(t) => Err(self.error(ErrorValue::UnexpectedToken(t))),
+                None => Err(self.error(ErrorValue::UnexpectedEOF)),
+            },
+            TokenValue::LeftBracket => {
+                let mut items = Vec::new();
+                let mut rest = None;
+
+                self.ignore_whitespace();
+                while !self.has(TokenValue::RightBracket) {
+                    if self.take(TokenValue::Ellipsis).is_some() {
+                        // The rest has to come last
+                        rest = Some(Box::new(self.pattern()?));
+                        self.ignore_whitespace();
+                        break;
+                    }
+
+                    items.push(self.pattern()?);
+                    self.ignore_whitespace();
                 }
 
-                for t in params.iter() {
-                    self.expression(true)?;
-                    if !self.last_type.satisfies(t.clone()) {
-                        return Err(self.error(ErrorValue::ArgumentTypeMismatch(t.clone(), self.last_type.clone())));
+                self.expect(TokenValue::RightBracket)?;
+
+                Ok(Pattern::Array(items, rest))
+            },
+            TokenValue::LeftBrace => {
+                let mut members = Vec::new();
+
+                self.ignore_whitespace();
+                while !self.has(TokenValue::RightBrace) {
+                    match self.next() {
+                        Some(Token { value: TokenValue::Identifier(name), .. }) => {
+                            if self.take(TokenValue::Colon).is_some() {
+                                let pattern = self.pattern()?;
+                                members.push((name, pattern));
+                            } else {
+                                members.push((name.clone(), Pattern::Binding(name)));
+                            }
+                        },
+                        Some(t) => return Err(self.error(ErrorValue::UnexpectedToken(t))),
+                        None => return Err(self.error(ErrorValue::UnexpectedEOF)),
                     }
+                    self.ignore_whitespace();
                 }
 
-                self.get_function().chunk.write_pair(op::CALL, params.len() as u16);
-
-                self.last_type = *return_type;
+                self.expect(TokenValue::RightBrace)?;
 
-                Ok(())
-            },
-            t => {
-                self.last_type = t;
-                Ok(())
+                Ok(Pattern::Block(members))
             },
+            _ => Err(self.error(ErrorValue::UnexpectedToken(t))),
         }
     }
 
-    fn type_(&mut self) -> Result<Type, Error> {
-        self.ignore_whitespace();
+    /// Whether a pattern matches every value of the type it's checked against
+    fn irrefutable(pattern: &Pattern) -> bool {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => true,
+            Pattern::Block(members) => members.iter().all(|(_, member)| Self::irrefutable(member)),
+            Pattern::Literal(_, _) | Pattern::Array(_, _) | Pattern::Variant(_, _) => false,
+        }
+    }
+
+    /// Whether the bracket or brace just consumed opens a pattern followed by `=`
+    fn destructuring_ahead(&mut self) -> bool {
+        let mut tokens = self.tokens.clone();
+        let mut depth = 1;
+
+        while depth > 0 {
+            match tokens.next().map(|t| t.value) {
+                Some(TokenValue::LeftBracket | TokenValue::LeftBrace) => depth += 1,
+                Some(TokenValue::RightBracket | TokenValue::RightBrace) => depth -= 1,
+                Some(_) => {},
+                None => return false,
+            }
+        }
+
+        matches!(tokens.next(), Some(Token { value: TokenValue::Equals, .. }))
+    }
+
+    fn destructuring(&mut self, t: Token) -> Result<(), Error> {
+        let pattern = self.pattern_from(t)?;
+        self.expect(TokenValue::Equals)?;
+
+        // Compile the value being destructured
+        self.get_function().begin_scope();
+        self.expression(true)?;
+        self.get_function().end_scope();
+
+        let t = self.last_type.clone();
+        if t == Type::Nil {
+            return Err(self.error(ErrorValue::TypeMismatch(Type::Nil, Type::Any)));
+        }
+
+        // Keep the value in an unnamed local to bind the parts from
+        self.get_function().chunk.write(op::PUSH_LOCAL);
+        let subject = self.get_function().add_local(String::new(), t.clone());
+        self.destructure(&pattern, subject, t)?;
+
+        // Emit nil return value from assignment
+        self.last_type = Type::Nil;
+
+        Ok(())
+    }
+
+    /// Bind the names in a pattern from a local, failing at runtime if it doesn't match
+    fn destructure(&mut self, pattern: &Pattern, subject: usize, type_: Type) -> Result<(), Error> {
+        let mut fail_jumps = Vec::new();
+        self.test_pattern(pattern, subject, &mut Vec::new(), type_.clone(), &mut fail_jumps)?;
+
+        if !fail_jumps.is_empty() {
+            let jump = self.get_function().chunk.code.len();
+            self.get_function().chunk.write_pair(op::JUMP, 0);
+
+            for jump in fail_jumps {
+                self.patch_jump(jump);
+            }
+
+            self.get_function().chunk.write_pair(op::GET_LOCAL, subject as u16);
+            self.get_function().chunk.write(op::NO_MATCH);
+
+            self.patch_jump(jump);
+        }
+
+        self.bind_pattern(pattern, subject, &mut Vec::new(), type_)
+    }
+
+    /// The type of value a pattern accepts, using the types inferred for its bindings
+    fn pattern_type(&mut self, pattern: &Pattern) -> Type {
+        match pattern {
+            Pattern::Wildcard => Type::Any,
+            Pattern::Binding(name) => match self.get_function().resolve(name) {
+                Some(local) if local.type_ != Type::Unknown => local.type_.clone(),
+                Some(_) => Type::Any,
+                None => Type::Unknown,
+            },
+            Pattern::Literal(_, t) => t.clone(),
+            Pattern::Array(items, rest) => {
+                let mut item_types: Vec<Type> = items.iter().map(|item| self.pattern_type(item)).collect();
+                if let Some(rest) = rest {
+                    if let Type::Array(t) = self.pattern_type(rest) {
+                        item_types.push(*t);
+                    }
+                }
+
+                // The first item with a known type decides the type of the rest
+                let item_type = item_types.iter()
+                    .find(|t| !matches!(t, Type::Unknown | Type::Any))
+                    .or(item_types.first())
+                    .cloned()
+                    .unwrap_or(Type::Unknown);
+
+                Type::Array(Box::new(item_type))
+            },
+            Pattern::Block(members) => Type::Block(members.iter().map(|(name, member)| (name.clone(), self.pattern_type(member))).collect()),
+            Pattern::Variant(name, _) => self.variant(name).map(|(union, _)| union).unwrap_or(Type::Unknown),
+        }
+    }
+
+    /// Push the part of the subject that a path leads to
+    fn load_path(&mut self, subject: usize, path: &[Access]) {
+        self.get_function().chunk.write_pair(op::GET_LOCAL, subject as u16);
+        for access in path {
+            let (key, op) = match access {
+                Access::Index(i) => (Value::Number(*i), op::INDEX),
+                Access::Member(name) => (Value::Object(Object::String(name.clone())), op::INDEX),
+                Access::Rest(i) => (Value::Number(*i), op::SLICE),
+                Access::Payload => {
+                    self.get_function().chunk.write(op::UNTAG);
+                    continue;
+                },
+            };
+
+            let constant = self.get_function().chunk.add_constant(key);
+            self.get_function().chunk.write_pair(op::PUSH, constant);
+            self.get_function().chunk.write(op);
+        }
+    }
+
+    fn item_type(&mut self, type_: &Type) -> Result<Type, Error> {
+        match type_.resolved() {
+            Type::Array(t) => Ok(*t.clone()),
+            Type::Any | Type::Unknown => Ok(Type::Any),
+            _ => Err(self.error(ErrorValue::TypeMismatch(type_.clone(), Type::Array(Box::new(Type::Any))))),
+        }
+    }
+
+    fn member_type(&mut self, type_: &Type, name: &str) -> Result<Type, Error> {
+        match type_.resolved() {
+            Type::Block(members) => match members.iter().find(|(n, _)| n == name) {
+                Some((_, t)) => Ok(t.clone()),
+                None => Err(self.error(ErrorValue::InvalidGetIdentifier(name.to_string()))),
+            },
+            Type::Any | Type::Unknown => Ok(Type::Any),
+            _ => Err(self.error(ErrorValue::InvalidGetTarget(type_.clone()))),
+        }
+    }
+
+    /// The type of a variant's payload, when it can be part of the type
+    fn payload_type(&mut self, type_: &Type, name: &str) -> Result<Type, Error> {
+        // A union from another file has its own variants, whatever this file declares with the same names
+        if let Type::Union(_, variants) = type_.resolved() {
+            if let Some((_, payload)) = variants.iter().find(|(n, _)| n == name) {
+                return Ok(payload.clone());
+            }
+        }
+
+        let (union, payload) = match self.variant(name) {
+            Some(v) => v,
+            None => return Err(self.error(ErrorValue::InvalidGetIdentifier(name.to_string()))),
+        };
+
+        match type_.resolved() {
+            Type::Any | Type::Unknown => Ok(payload),
+            _ => Err(self.error(ErrorValue::TypeMismatch(type_.clone(), union))),
+        }
+    }
+
+    /// Emit the checks for a pattern, collecting the jumps taken when it doesn't match
+    fn test_pattern(&mut self, pattern: &Pattern, subject: usize, path: &mut Vec<Access>, type_: Type, fail_jumps: &mut Vec<usize>) -> Result<(), Error> {
+        match pattern {
+            Pattern::Wildcard | Pattern::Binding(_) => {},
+            Pattern::Literal(value, literal_type) => {
+                let comparable = matches!(type_, Type::Any | Type::Unknown)
+                    || matches!((&type_, literal_type), (Type::Number | Type::Float, Type::Number | Type::Float))
+                    || literal_type.satisfies(type_.clone());
+                if !comparable {
+                    return Err(self.error(ErrorValue::InvalidComparison(type_, literal_type.clone())));
+                }
+
+                self.load_path(subject, path);
+                let constant = self.get_function().chunk.add_constant(value.clone());
+                self.get_function().chunk.write_pair(op::PUSH, constant);
+                self.get_function().chunk.write(op::EQUAL);
+
+                fail_jumps.push(self.get_function().chunk.code.len());
+                self.get_function().chunk.write_pair(op::JUMP_IF_FALSE, 0);
+            },
+            Pattern::Array(items, rest) => {
+                let item_type = self.item_type(&type_)?;
+
+                // Check the length first so indexing can't go out of bounds
+                self.resolve_global("length");
+                self.load_path(subject, path);
+                self.get_function().chunk.write_pair(op::CALL, 1);
+                let constant = self.get_function().chunk.add_constant(Value::Number(items.len() as i64));
+                self.get_function().chunk.write_pair(op::PUSH, constant);
+                if rest.is_some() {
+                    self.get_function().chunk.write(op::GREATER_THAN_EQUAL);
+                } else {
+                    self.get_function().chunk.write(op::EQUAL);
+                }
+
+                fail_jumps.push(self.get_function().chunk.code.len());
+                self.get_function().chunk.write_pair(op::JUMP_IF_FALSE, 0);
+
+                for (i, item) in items.iter().enumerate() {
+                    path.push(Access::Index(i as i64));
+                    self.test_pattern(item, subject, path, item_type.clone(), fail_jumps)?;
+                    path.pop();
+                }
+
+                if let Some(rest) = rest {
+                    path.push(Access::Rest(items.len() as i64));
+                    self.test_pattern(rest, subject, path, Type::Array(Box::new(item_type)), fail_jumps)?;
+                    path.pop();
+                }
+            },
+            Pattern::Block(members) => {
+                for (name, member) in members {
+                    let member_type = self.member_type(&type_, name)?;
+                    path.push(Access::Member(name.clone()));
+                    self.test_pattern(member, subject, path, member_type, fail_jumps)?;
+                    path.pop();
+                }
+            },
+            Pattern::Variant(name, payload) => {
+                let payload_type = self.payload_type(&type_, name)?;
+
+                self.load_path(subject, path);
+                let constant = self.get_function().chunk.add_constant(Value::Object(Object::String(name.clone())));
+                self.get_function().chunk.write_pair(op::IS_VARIANT, constant);
+
+                fail_jumps.push(self.get_function().chunk.code.len());
+                self.get_function().chunk.write_pair(op::JUMP_IF_FALSE, 0);
+
+                path.push(Access::Payload);
+                self.test_pattern(payload, subject, path, payload_type, fail_jumps)?;
+                path.pop();
+            },
+        }
+
+        Ok(())
+    }
+
+    /// Add a local for each name bound by a pattern that is known to match
+    fn bind_pattern(&mut self, pattern: &Pattern, subject: usize, path: &mut Vec<Access>, type_: Type) -> Result<(), Error> {
+        match pattern {
+            Pattern::Wildcard | Pattern::Literal(_, _) => {},
+            Pattern::Binding(name) => {
+                if self.get_function().resolve(name).is_some() {
+                    return Err(self.error(ErrorValue::DuplicateBinding(name.clone())));
+                }
+
+                self.load_path(subject, path);
+                self.get_function().chunk.write(op::PUSH_LOCAL);
+                self.get_function().add_local(name.clone(), type_);
+            },
+            Pattern::Array(items, rest) => {
+                let item_type = self.item_type(&type_)?;
+
+                for (i, item) in items.iter().enumerate() {
+                    path.push(Access::Index(i as i64));
+                    self.bind_pattern(item, subject, path, item_type.clone())?;
+                    path.pop();
+                }
+
+                if let Some(rest) = rest {
+                    path.push(Access::Rest(items.len() as i64));
+                    self.bind_pattern(rest, subject, path, Type::Array(Box::new(item_type)))?;
+                    path.pop();
+                }
+            },
+            Pattern::Block(members) => {
+                for (name, member) in members {
+                    let member_type = self.member_type(&type_, name)?;
+                    path.push(Access::Member(name.clone()));
+                    self.bind_pattern(member, subject, path, member_type)?;
+                    path.pop();
+                }
+            },
+            Pattern::Variant(name, payload) => {
+                let payload_type = self.payload_type(&type_, name)?;
+                path.push(Access::Payload);
+                self.bind_pattern(payload, subject, path, payload_type)?;
+                path.pop();
+            },
+        }
+
+        Ok(())
+    }
+
+    fn for_(&mut self) -> Result<(), Error> {
+        // Already consumed the for
+        let pattern = self.pattern()?;
+
+        self.expect(TokenValue::In)?;
+
+        // Compile the array being iterated over
+        self.expression(true)?;
+        if self.last_type == Type::Unknown {
+            self.assert_type(Type::Array(Box::new(Type::Any)))?;
+        }
+
+        let item_type = match self.last_type.resolved().clone() {
+            Type::Array(t) => *t,
+            _ => return Err(self.error(ErrorValue::TypeMismatch(self.last_type.clone(), Type::Array(Box::new(Type::Any))))),
+        };
+
+        // Compile the body as a function of the current item
+        self.function_body(String::new(), vec![(pattern, item_type)], None)?;
+
+        let return_type = match self.last_type.clone() {
+            Type::Function(_, return_type) => *return_type,
+            _ => unreachable!(),
+        };
+
+        // Like a group, concatenate the result of each iteration unless there are none
+        if return_type == Type::Nil {
+            self.get_function().chunk.write_pair(op::LOOP, 0);
+            self.last_type = Type::Nil;
+        } else {
+            self.get_function().chunk.write_pair(op::LOOP, 1);
+            self.last_type = Type::String;
+        }
+
+        Ok(())
+    }
+
+    fn import(&mut self) -> Result<(), Error> {
+        // Already consumed the import
+        let relative = match self.next() {
+            Some(Token { value: TokenValue::String(s), .. }) => s,
+            Some(t) => return Err(self.error(ErrorValue::UnexpectedToken(t))),
+            None => return Err(self.error(ErrorValue::UnexpectedEOF)),
+        };
+
+        // Paths are relative to the importing file
+        let path = self.path.parent().unwrap_or(Path::new("")).join(&relative);
+        let path = match fs::canonicalize(&path) {
+            Ok(p) => p,
+            Err(_) => return Err(self.error(ErrorValue::ImportFailed(relative))),
+        };
+
+        if self.importing.contains(&path) {
+            return Err(self.error(ErrorValue::ImportCycle(relative)));
+        }
+
+        // Each file is only compiled once
+        let index = match self.modules.iter().position(|m| m.path == path) {
+            Some(i) => i,
+            None => self.module(path)?,
+        };
+
+        let function = self.modules[index].function.clone();
+        let constant = self.get_function().chunk.add_constant(Value::Object(Object::Function(function)));
+        self.get_function().chunk.write_pair(op::PUSH, constant);
+        self.get_function().chunk.write_pair(op::IMPORT, index as u16);
+
+        self.last_type = self.modules[index].type_.clone();
+
+        Ok(())
+    }
+
+    /// Compile an imported file on its own, returning its index in the modules
+    fn module(&mut self, path: PathBuf) -> Result<usize, Error> {
+        let source = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(_) => return Err(self.error(ErrorValue::ImportFailed(path.display().to_string()))),
+        };
+
+        // Nothing from the importing file is visible to the module
+        let tokens = std::mem::replace(&mut self.tokens, Scanner::new(source).peekable());
+        let functions = std::mem::take(&mut self.functions);
+        let unresolved_types = std::mem::take(&mut self.unresolved_types);
+        let arguments = std::mem::replace(&mut self.arguments, false);
+        let piped = self.piped.take();
+        let expected = self.expected.take();
+        let passing = std::mem::replace(&mut self.passing, false);
+        let narrowed = std::mem::take(&mut self.narrowed);
+        let generics = std::mem::take(&mut self.generics);
+        let types = std::mem::take(&mut self.types);
+        let outer_path = std::mem::replace(&mut self.path, path.clone());
+        let line = std::mem::replace(&mut self.line, 1);
+        self.importing.push(path.clone());
+
+        let result = self.module_body();
+
+        self.importing.pop();
+        self.tokens = tokens;
+        self.functions = functions;
+        self.unresolved_types = unresolved_types;
+        self.arguments = arguments;
+        self.piped = piped;
+        self.expected = expected;
+        self.passing = passing;
+        self.narrowed = narrowed;
+        self.generics = generics;
+        self.types = types;
+        self.path = outer_path;
+        self.line = line;
+
+        let function = match result {
+            Ok(f) => f,
+            Err(e) => return Err(self.error(ErrorValue::InModule(path.display().to_string(), Box::new(e)))),
+        };
+
+        self.modules.push(Module {
+            path,
+            function: Rc::new(function),
+            type_: self.last_type.clone(),
+        });
+
+        Ok(self.modules.len() - 1)
+    }
+
+    /// Compile a file like the body of a block, evaluating to its top level bindings
+    fn module_body(&mut self) -> Result<Function, Error> {
+        self.functions.push(Function::new(String::new(), Chunk::new()));
+        self.get_function().begin_scope();
+
+        while self.peek().is_some() {
+            self.ignore_whitespace();
+            self.statement(false)?;
+            self.ignore_whitespace();
+        }
+
+        self.assert_defined()?;
+        self.make_block(&[]);
+
+        self.get_function().end_scope();
+
+        Ok(self.functions.pop().unwrap())
+    }
+
+    fn resolve_upvalue(&mut self, name: &str) -> bool {
+        // Resolve, flagging upvalues top to bottom
+        for i in (0..(self.functions.len() - 1)).rev() {
+            if let Some(local) = &self.functions[i].resolve(name).cloned() {
+                // Add the upvalue to every function between the local and this one
+                let mut is_local = true;
+                let mut index = local.index;
+                for j in (i + 1)..self.functions.len() {
+                    index = self.functions[j].add_upvalue(index, is_local);
+                    is_local = false;
+                }
+
+                self.last_type = self.local_type(i, local);
+                self.get_function().chunk.write_pair(op::GET_UPVALUE, index as u16);
+                if local.cell {
+                    self.get_function().chunk.write(op::READ_CELL);
+                }
+
+                return true
+            }
+        }
+
+        false
+    }
+
+    fn resolve_local(&mut self, name: &str) -> bool {
+        if let Some(local) = self.get_function().resolve(name).cloned() {
+            self.last_type = self.local_type(self.functions.len() - 1, &local);
+
+            self.unresolved_types.push(name.to_string());
+            self.get_function().chunk.write_pair(op::GET_LOCAL, local.index as u16);
+            if local.cell {
+                self.get_function().chunk.write(op::READ_CELL);
+            }
+            return true;
+        }
+
+        false
+    }
+
+    fn resolve_global(&mut self, name: &str) -> bool {
+        let globals = self.globals.clone();
+        for (i, g) in globals.iter().clone().enumerate() {
+            if g.name == name {
+                self.get_function().chunk.write_pair(op::GET_GLOBAL, i as u16);
+                self.last_type = g.type_.clone();
+                return true;
+            }
+        }
+
+       false
+    }
+
+    fn call(&mut self, name: String) -> Result<(), Error> {
+        if let Some(local) = self.get_function().resolve(&name).cloned() {
+            if !local.defined {
+                // Only a nested function can refer to it before its definition, since it runs later
+                return Err(self.error(ErrorValue::UsedBeforeDefinition(name)));
+            }
+        }
+
+        if self.resolve_local(&name) || self.resolve_upvalue(&name) {
+            self.execute_call()?;
+            self.read = Some((name, self.position()));
+            Ok(())
+        } else if self.resolve_global(&name) {
+            self.execute_call()
+        } else if let Some((union, payload)) = self.variant(&name) {
+            self.construct(name, union, payload)
+        } else if self.functions.iter().any(|f| f.name == name) {
+            // Only functions with a declared signature can refer to themselves
+            Err(self.error(ErrorValue::RecursiveCall(name)))
+        } else {
+            self.assignment(name)
+        }
+    }
+
+    fn execute_call(&mut self) -> Result<(), Error> {
+        // Each call binds the type variables of a generic function afresh
+        match self.last_type.resolved().clone() {
+            Type::Function(params, return_type) => {
+                if std::mem::take(&mut self.passing) {
+                    return Ok(());
+                }
+
+                let piped = if params.is_empty() { None } else { self.piped.take() };
+
+                if piped.is_none() && (self.has(TokenValue::Newline) | self.has(TokenValue::RightParen) | self.has(TokenValue::Pipe) | self.peek().is_none()) {
+                    // Not a call, just passing a function around
+                    return Ok(())
+                }
+
+                // A piped value is the first argument
+                let mut bindings = Bindings::new();
+                let mut rest = params.iter();
+                if let Some(index) = piped {
+                    let t = rest.next().unwrap();
+                    let piped_type = self.get_function().locals[index].type_.clone();
+                    if !piped_type.unify(t, &mut bindings) {
+                        return Err(self.error(ErrorValue::ArgumentTypeMismatch(t.substitute(&bindings), piped_type)));
+                    }
+
+                    self.get_function().chunk.write_pair(op::GET_LOCAL, index as u16);
+                }
+
+                let arguments = std::mem::replace(&mut self.arguments, true);
+                for t in rest {
+                    // A lambda can take its parameter types from what the call expects
+                    if self.has(TokenValue::BackSlash) {
+                        self.expected = Some(t.substitute(&bindings));
+                    }
+
+                    if let Type::Function(_, _) = t.resolved() {
+                        // A function passed as an argument isn't called with the arguments after it
+                        self.passing = matches!(self.peek().map(|t| &t.value), Some(TokenValue::Identifier(_)));
+                        self.coalesce()?;
+                        self.passing = false;
+                    } else {
+                        self.expression(true)?;
+                    }
+
+                    if !self.last_type.unify(t, &mut bindings) {
+                        return Err(self.error(ErrorValue::ArgumentTypeMismatch(t.substitute(&bindings), self.last_type.clone())));
+                    }
+                }
+                self.arguments = arguments;
+
+                self.get_function().chunk.write_pair(op::CALL, params.len() as u16);
+
+                // Generic functions return what their type variables were bound to
+                self.last_type = return_type.substitute(&bindings);
+
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Wrap a payload in a variant of a union
+    fn construct(&mut self, variant: String, union: Type, payload: Type) -> Result<(), Error> {
+        if payload == Type::Block(Vec::new()) {
+            // Nothing to carry
+            self.get_function().chunk.write_pair(op::MAKE_BLOCK, 0);
+        } else {
+            // The payload is taken like an argument
+            let arguments = std::mem::replace(&mut self.arguments, true);
+            self.expression(true)?;
+            self.arguments = arguments;
+
+            if !self.last_type.satisfies(payload.clone()) {
+                return Err(self.error(ErrorValue::ArgumentTypeMismatch(payload, self.last_type.clone())));
+            }
+        }
+
+        let constant = self.get_function().chunk.add_constant(Value::Object(Object::String(variant)));
+        self.get_function().chunk.write_pair(op::TAG, constant);
+
+        self.last_type = union;
+
+        Ok(())
+    }
+
+    /// The union a variant belongs to, and the type of its payload
+    fn variant(&self, name: &str) -> Option<(Type, Type)> {
+        self.types.iter().find_map(|(_, t)| match t {
+            Type::Union(_, variants) => variants.iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, payload)| (t.clone(), payload.clone())),
+            _ => None,
+        })
+    }
+
+    fn type_declaration(&mut self) -> Result<(), Error> {
+        // Already consumed the type
+        let name = self.identifier()?;
+        if self.types.iter().any(|(n, _)| *n == name) || self.builtin_type(&name).is_some() {
+            return Err(self.error(ErrorValue::DuplicateBinding(name)));
+        }
+
+        self.expect(TokenValue::Equals)?;
+
+        // A name that isn't a type yet starts the variants of a union, as long as it has a payload or other variants
+        self.ignore_whitespace();
+        let union = match self.peek().map(|t| t.value.clone()) {
+            Some(TokenValue::Bar) => true,
+            Some(TokenValue::Identifier(s)) => self.builtin_type(&s).is_none() && !self.generics.contains(&s) && !self.types.iter().any(|(n, _)| *n == s) && self.variant_ahead(),
+            _ => false,
+        };
+
+        // The name can be used in its own definition, for types like trees
+        let named = NamedType::new(name.clone());
+        self.types.push((name.clone(), Type::Named(named.clone())));
+
+        if union {
+            let t = self.union(named.clone())?;
+            named.define(t.clone());
+            self.types.last_mut().unwrap().1 = t;
+        } else {
+            let t = self.type_()?;
+            if t == Type::Named(named.clone()) {
+                return Err(self.error(ErrorValue::InvalidTypeAnnotation(name)));
+            }
+            named.define(t);
+        }
+
+        // Emit nil return value from declaration
+        self.last_type = Type::Nil;
+
+        Ok(())
+    }
+
+    fn union(&mut self, named: Rc<NamedType>) -> Result<Type, Error> {
+        // Variants can start on their own lines, each after a bar
+        self.take(TokenValue::Bar);
+
+        let mut variants: Vec<(String, Type)> = Vec::new();
+        loop {
+            self.ignore_whitespace();
+            let variant = self.identifier()?;
+            if variants.iter().any(|(n, _)| *n == variant) || self.variant(&variant).is_some() {
+                return Err(self.error(ErrorValue::DuplicateBinding(variant)));
+            }
+
+            let payload = if self.has(TokenValue::LeftBrace) {
+                self.base_type()?
+            } else {
+                Type::Block(Vec::new())
+            };
+            variants.push((variant, payload));
+
+            if !self.bar_ahead() {
+                break;
+            }
+
+            self.ignore_whitespace();
+            self.expect(TokenValue::Bar)?;
+        }
+
+        Ok(Type::Union(named, variants))
+    }
+
+    /// Whether another variant follows, possibly on the next line
+    fn bar_ahead(&mut self) -> bool {
+        Self::starts_with_bar(self.tokens.clone())
+    }
+
+    /// Whether the next name is a variant, with a payload or another variant after it
+    fn variant_ahead(&mut self) -> bool {
+        let mut tokens = self.tokens.clone();
+        tokens.next();
+        tokens.peek().is_some_and(|t| t.value == TokenValue::LeftBrace) || Self::starts_with_bar(tokens)
+    }
+
+    fn starts_with_bar(mut tokens: Peekable<Scanner>) -> bool {
+        loop {
+            match tokens.next().map(|t| t.value) {
+                Some(TokenValue::Newline) => continue,
+                Some(TokenValue::Bar) => return true,
+                _ => return false,
+            }
+        }
+    }
+
+    fn identifier(&mut self) -> Result<String, Error> {
+        match self.next() {
+            Some(Token { value: TokenValue::Identifier(name), .. }) => Ok(name),
+            Some(t) => Err(self.error(ErrorValue::UnexpectedToken(t))),
+            None => Err(self.error(ErrorValue::UnexpectedEOF)),
+        }
+    }
+
+    fn builtin_type(&self, name: &str) -> Option<Type> {
+        match name {
+            "string" => Some(Type::String),
+            "number" => Some(Type::Number),
+            "float" => Some(Type::Float),
+            "boolean" => Some(Type::Boolean),
+            "nil" => Some(Type::Nil),
+            "never" => Some(Type::Never),
+            "any" => Some(Type::Any),
+            _ => None,
+        }
+    }
+
+    fn type_(&mut self) -> Result<Type, Error> {
+        let t = self.base_type()?;
+
+        // A question mark makes it optional
+        if self.take(TokenValue::Question).is_some() {
+            Ok(t.optional())
+        } else {
+            Ok(t)
+        }
+    }
+
+    fn base_type(&mut self) -> Result<Type, Error> {
+        self.ignore_whitespace();
         if let Some(t) = self.next() {
             return match t.value {
-                TokenValue::Identifier(s) => match s.as_str() {
-                    "string" => Ok(Type::String),
-                    "number" => Ok(Type::Number),
-                    "boolean" => Ok(Type::Boolean),
-                    "nil" => Ok(Type::Nil),
-                    "any" => Ok(Type::Any),
-                    _ => Err(self.error(ErrorValue::InvalidTypeAnnotation(s))),
+                TokenValue::Identifier(s) if self.generics.contains(&s) => Ok(Type::Var(s)),
+                TokenValue::Identifier(s) => match self.builtin_type(&s) {
+                    Some(t) => Ok(t),
+                    None => match self.types.iter().find(|(n, _)| *n == s) {
+                        Some((_, t)) => Ok(t.clone()),
+                        None => Err(self.error(ErrorValue::UnknownType(s))),
+                    },
                 },
                 TokenValue::BackSlash => {
                     // Function
@@ -658,7 +1892,7 @@ impl Compiler {
                         params.push(self.type_()?);
                     }
 
-                    if !self.take(TokenValue::Equals).is_some() {
+                    if self.take(TokenValue::Equals).is_none() {
                         return Err(self.error(ErrorValue::InvalidTypeAnnotation("Function".to_string())));
                     }
 
@@ -672,7 +1906,7 @@ impl Compiler {
                     self.ignore_whitespace();
                     let t = self.type_()?;
                     self.ignore_whitespace();
-                    if !self.take(TokenValue::RightParen).is_some() {
+                    if self.take(TokenValue::RightParen).is_none() {
                         return Err(self.error(ErrorValue::InvalidTypeAnnotation("Group".to_string())));
                     }
 
@@ -685,6 +1919,13 @@ impl Compiler {
                         self.ignore_whitespace();
                         if let Some(t) = self.next() {
                             if let TokenValue::Identifier(name) = t.value {
+                                if self.take(TokenValue::Colon).is_some() {
+                                    // Constant
+                                    members.push((name, self.type_()?));
+                                    self.ignore_whitespace();
+                                    continue;
+                                }
+
                                 self.ignore_whitespace();
     
                                 // Get params
@@ -700,7 +1941,7 @@ impl Compiler {
                                 // Get return type
                                 let return_type = self.type_()?;
     
-                                if params.len() > 0 {
+                                if !params.is_empty() {
                                     // Function type
                                     members.push((name, Type::Function(params, Box::new(return_type))));
                                 } else {
@@ -727,7 +1968,7 @@ impl Compiler {
                     self.ignore_whitespace();
                     let t = self.type_()?;
                     self.ignore_whitespace();
-                    if !self.take(TokenValue::RightBracket).is_some() {
+                    if self.take(TokenValue::RightBracket).is_none() {
                         return Err(self.error(ErrorValue::InvalidTypeAnnotation("Array".to_string())));
                     }
 
@@ -740,65 +1981,136 @@ impl Compiler {
         Err(self.error(ErrorValue::UnexpectedEOF))
     }
 
-    fn function(&mut self, name: String) -> Result<(), Error> {
+    fn signature(&mut self) -> Result<(Params, Option<Type>), Error> {
         let mut params = Vec::new();
-        let mut valid = false;
+        let mut return_type = None;
 
-        while let Some(t) = self.next() {
-            match t.value {
-                TokenValue::Equals => {
-                    valid = true;
-                    break;
-                },
+        // Type variables come first, and stay in scope until the caller is done with the function
+        if self.take(TokenValue::LessThan).is_some() {
+            while self.take(TokenValue::GreaterThan).is_none() {
+                let name = self.identifier()?;
+                self.generics.push(name);
+            }
+        }
+
+        while let Some(t) = self.peek() {
+            match &t.value {
                 TokenValue::Identifier(s) => {
+                    let s = s.clone();
+                    self.next();
                     if self.take(TokenValue::Colon).is_some() {
-                        params.push((s.clone(), self.type_()?));
+                        params.push((Pattern::Binding(s), self.type_()?));
                     } else {
-                        params.push((s.clone(), Type::Unknown));
+                        params.push((Pattern::Binding(s), Type::Unknown));
                     }
                 },
-                _ => {
+                TokenValue::LeftBracket | TokenValue::LeftBrace => {
+                    // Destructure the argument
+                    let t = self.next().unwrap();
+                    let pattern = self.pattern_from(t)?;
+                    if self.take(TokenValue::Colon).is_some() {
+                        params.push((pattern, self.type_()?));
+                    } else {
+                        params.push((pattern, Type::Unknown));
+                    }
+                },
+                TokenValue::Arrow => {
                     self.next();
-                    break;
+                    return_type = Some(self.type_()?);
                 },
+                _ => break,
             }
         }
 
-        if !valid {
-            return Err(self.error(ErrorValue::UnexpectedEOF));
+        Ok((params, return_type))
+    }
+
+    fn function(&mut self, name: String) -> Result<(), Error> {
+        let expected = self.expected.take();
+        let generics = self.generics.len();
+        let (mut params, return_type) = self.signature()?;
+        self.expect(TokenValue::Equals)?;
+
+        // Parameters without annotations have the types the call expects
+        if let Some(Type::Function(types, _)) = expected {
+            if types.len() == params.len() {
+                for ((_, t), expected) in params.iter_mut().zip(types) {
+                    if *t == Type::Unknown && expected != Type::Any {
+                        *t = expected;
+                    }
+                }
+            }
         }
 
-        // Begin a new function
+        let result = self.function_body(name, params, return_type);
+        self.generics.truncate(generics);
+
+        result
+    }
+
+    fn function_body(&mut self, name: String, params: Params, declared_type: Option<Type>) -> Result<(), Error> {
+        // Begin a new function, which can't take a value piped outside of it
         self.functions.push(Function::new(name.clone(), Chunk::new()));
+        let piped = self.piped.take();
 
         self.get_function().begin_scope();
 
-        // Add params to locals
-        for (name, type_) in params.iter().rev() {
-            // TODO: read the param types
-            self.get_function().add_local(name.clone(), type_.clone());
+        // With a full signature, named functions can refer to themselves
+        if let Some(t) = &declared_type {
+            if !name.is_empty() && params.iter().all(|(_, t)| *t != Type::Unknown) {
+                let self_type = Type::Function(params.iter().map(|(_, t)| t.clone()).collect(), Box::new(t.clone()));
+                self.get_function().recursive = true;
+                self.get_function().add_local(name.clone(), self_type);
+                self.get_function().chunk.write(op::PUSH_LOCAL);
+            }
+        }
+
+        // Add params to locals, leaving destructured ones unnamed
+        let mut indices = vec![0; params.len()];
+        for (i, (pattern, type_)) in params.iter().enumerate().rev() {
+            let (name, type_) = match pattern {
+                Pattern::Binding(name) => (name.clone(), type_.clone()),
+                _ if *type_ == Type::Unknown => (String::new(), self.pattern_type(pattern)),
+                _ => (String::new(), type_.clone()),
+            };
+            indices[i] = self.get_function().add_local(name, type_);
             self.get_function().chunk.write(op::PUSH_LOCAL);
         }
 
+        for ((pattern, _), index) in params.iter().zip(indices.iter()) {
+            if !matches!(pattern, Pattern::Binding(_)) {
+                let type_ = self.get_function().locals[*index].type_.clone();
+                self.destructure(pattern, *index, type_)?;
+            }
+        }
+
         // Compile the body
         self.expression(true)?;
+        if let Some(t) = declared_type {
+            self.assert_type(t)?;
+        }
         let return_type = self.last_type.clone();
 
         // Get local types of each param
         let mut param_types = Vec::new();
-        for (name, _) in params.iter() {
-            if let Some(local) = self.get_function().resolve(name).cloned() {
-                if local.type_ == Type::Unknown {
-                    param_types.push(Type::Any);
-                } else {
-                    param_types.push(local.type_.clone());
-                }
+        for ((pattern, type_), index) in params.iter().zip(indices.iter()) {
+            let local_type = self.get_function().locals[*index].type_.clone();
+            match pattern {
+                Pattern::Binding(_) if local_type == Type::Unknown => param_types.push(Type::Any),
+                Pattern::Binding(_) => param_types.push(local_type),
+                // Unannotated patterns accept whatever their bindings were used as
+                _ if *type_ == Type::Unknown => {
+                    let t = self.pattern_type(pattern);
+                    param_types.push(t);
+                },
+                _ => param_types.push(type_.clone()),
             }
         }
 
         self.get_function().end_scope();
 
         let func = self.functions.pop().unwrap();
+        self.piped = piped;
         let func = Rc::new(func);
 
         // Add function as constant
@@ -806,7 +2118,7 @@ impl Compiler {
         self.get_function().chunk.write_pair(op::PUSH, constant);
 
         // Handle closures when necessary
-        if func.upvalues.len() > 0 {
+        if !func.upvalues.is_empty() || func.recursive {
             // Make the closure
             self.get_function().chunk.write(op::MAKE_CLOSURE);
         }
@@ -843,10 +2155,22 @@ impl Compiler {
 
             Ok(())
         } else {
+            let generics = self.generics.len();
+            let (params, return_type) = self.signature()?;
+            let own = self.generics[generics..].to_vec();
+
+            // A full signature without a body declares the function ahead of its definition
+            if return_type.is_some() && (self.has(TokenValue::Newline) || self.peek().is_none()) {
+                self.generics.truncate(generics);
+                return self.declaration(name, params, return_type, &own);
+            }
+
             // Compile function body
-            self.function(name.clone())?;
+            self.expect(TokenValue::Equals)?;
+            self.function_body(name.clone(), params, return_type)?;
+            self.generics.truncate(generics);
             self.get_function().chunk.write(op::PUSH_LOCAL);
-            let t = self.last_type.clone();
+            let t = self.last_type.generalize(&own);
             self.get_function().add_local(name.clone(), t);
 
             // Emit assignment return value of nil
@@ -856,8 +2180,68 @@ impl Compiler {
         }
     }
 
+    fn declaration(&mut self, name: String, params: Params, return_type: Option<Type>, generics: &[String]) -> Result<(), Error> {
+        if params.iter().any(|(_, t)| *t == Type::Unknown) {
+            return Err(self.error(ErrorValue::InvalidTypeAnnotation(name)));
+        }
+
+        let t = Type::Function(params.into_iter().map(|(_, t)| t).collect(), Box::new(return_type.unwrap_or(Type::Nil)));
+        let t = t.generalize(generics);
+
+        self.get_function().chunk.write(op::MAKE_CELL);
+        self.get_function().chunk.write(op::PUSH_LOCAL);
+        self.get_function().declare_local(name, t);
+
+        // Emit nil return value from declaration
+        self.last_type = Type::Nil;
+
+        Ok(())
+    }
+
+    fn definition(&mut self, local: Local) -> Result<(), Error> {
+        let generics = self.generics.len();
+        let (mut params, mut return_type) = self.signature()?;
+        self.expect(TokenValue::Equals)?;
+
+        // Anything left out is taken from the declaration, as the body sees it
+        let declared = local.type_.rigid();
+        if let Type::Function(types, declared_return_type) = &declared {
+            if types.len() == params.len() {
+                for ((_, t), declared) in params.iter_mut().zip(types.iter()) {
+                    if *t == Type::Unknown {
+                        *t = declared.clone();
+                    }
+                }
+            }
+
+            if return_type.is_none() {
+                return_type = Some(*declared_return_type.clone());
+            }
+        }
+
+        self.function_body(local.name.clone(), params, return_type)?;
+        self.generics.truncate(generics);
+        if !self.last_type.satisfies(declared.clone()) {
+            return Err(self.error(ErrorValue::TypeMismatch(self.last_type.clone(), declared)));
+        }
+
+        // Fill in the declared cell
+        self.get_function().chunk.write_pair(op::SET_CELL, local.index as u16);
+        for l in self.get_function().locals.iter_mut() {
+            if l.index == local.index {
+                l.defined = true;
+            }
+        }
+
+        // Emit nil return value from definition
+        self.last_type = Type::Nil;
+
+        Ok(())
+    }
+
     fn group(&mut self) -> Result<(), Error> {   
         self.get_function().begin_scope();
+        let arguments = std::mem::replace(&mut self.arguments, false);
 
         let mut n = 0;
 
@@ -866,7 +2250,7 @@ impl Compiler {
         // Loop until right paren
         while !self.has(TokenValue::RightParen) {
             self.ignore_whitespace();
-            self.expression(true)?;
+            self.statement(true)?;
             self.ignore_whitespace();
 
             if self.last_type != Type::Nil {
@@ -879,6 +2263,8 @@ impl Compiler {
             return Err(self.error(ErrorValue::UnexpectedEOF));
         }
 
+        self.assert_defined()?;
+
         if n == 0 {
             // Since nil values don't actually exist...
             let empty_string = self.get_function().chunk.add_constant(Value::Object(Object::String(String::new())));
@@ -889,21 +2275,88 @@ impl Compiler {
             self.last_type = Type::String;
         }
 
+        self.arguments = arguments;
         self.get_function().end_scope();
 
         Ok(())
     }
 
+    fn template(&mut self, parts: Vec<TemplatePart>) -> Result<(), Error> {
+        let mut n = 0;
+
+        for part in parts {
+            match part {
+                TemplatePart::Text(s) => {
+                    if s.is_empty() {
+                        continue;
+                    }
+
+                    let constant = self.get_function().chunk.add_constant(Value::Object(Object::String(s)));
+                    self.get_function().chunk.write_pair(op::PUSH, constant);
+                    n += 1;
+                },
+                TemplatePart::Expression(source, line) => {
+                    // Compile the embedded expression from its own tokens
+                    let tokens = std::mem::replace(&mut self.tokens, Scanner::starting_at(source, line).peekable());
+                    let arguments = std::mem::replace(&mut self.arguments, false);
+
+                    self.ignore_whitespace();
+                    self.expression(true)?;
+                    self.ignore_whitespace();
+
+                    if let Some(t) = self.next() {
+                        return Err(self.error(ErrorValue::UnexpectedToken(t)));
+                    }
+
+                    self.tokens = tokens;
+                    self.arguments = arguments;
+
+                    if self.last_type != Type::Nil {
+                        n += 1;
+                    }
+                },
+            }
+        }
+
+        if n == 0 {
+            let empty_string = self.get_function().chunk.add_constant(Value::Object(Object::String(String::new())));
+            self.get_function().chunk.write_pair(op::PUSH, empty_string);
+        } else {
+            self.get_function().chunk.write_pair(op::CONCATENATE, n as u16);
+        }
+
+        self.last_type = Type::String;
+
+        Ok(())
+    }
+
     fn list(&mut self) -> Result<(), Error> {
         let mut count = 0;
-        let mut item_type = Type::Nil;
+        let mut parts = 0;
+        let mut item_type = Type::Never;
 
         self.get_function().begin_scope();
+        let arguments = std::mem::replace(&mut self.arguments, false);
 
         self.ignore_whitespace();
 
         while !self.has(TokenValue::RightBracket) {
             self.ignore_whitespace();
+            if self.take(TokenValue::Ellipsis).is_some() {
+                // Finish the items before the spread so they stay in order
+                if count > 0 {
+                    self.get_function().chunk.write_pair(op::MAKE_ARRAY, count);
+                    parts += 1;
+                    count = 0;
+                }
+
+                self.expression(true)?;
+                self.spread_items(&mut item_type)?;
+                parts += 1;
+                self.ignore_whitespace();
+                continue;
+            }
+
             self.expression(true)?;
             self.ignore_whitespace();
 
@@ -912,64 +2365,169 @@ impl Compiler {
             }
 
             count += 1;
-
-            if item_type == Type::Nil {
-                item_type = self.last_type.clone();
-            } else {
-                let t = self.last_type.clone();
-                let error = self.error(ErrorValue::ListItemTypeMismatch(item_type.clone(), t));
-                self.assert_type_with_error(item_type.clone(), error)?;
-            }
+            self.list_item(&mut item_type)?;
         }
         
         if self.take(TokenValue::RightBracket).is_none() {
             return Err(self.error(ErrorValue::UnexpectedEOF));
         }
 
-        self.get_function().chunk.write_pair(op::MAKE_ARRAY, count);
+        if count > 0 || parts == 0 {
+            self.get_function().chunk.write_pair(op::MAKE_ARRAY, count);
+            parts += 1;
+        }
+
+        if parts > 1 {
+            self.get_function().chunk.write_pair(op::MERGE, parts);
+        }
 
         self.last_type = Type::Array(Box::from(item_type));
 
+        self.arguments = arguments;
         self.get_function().end_scope();
 
         Ok(())
     }
 
+    /// Make sure the last value has the same type as the other items of a list
+    fn list_item(&mut self, item_type: &mut Type) -> Result<(), Error> {
+        if *item_type != Type::Any && item_type.satisfies(self.last_type.clone()) {
+            // The items so far fit this one, like an empty array in an array of arrays
+            *item_type = self.last_type.clone();
+        } else {
+            let t = self.last_type.clone();
+            let error = self.error(ErrorValue::ListItemTypeMismatch(item_type.clone(), t));
+            self.assert_type_with_error(item_type.clone(), error)?;
+        }
+
+        Ok(())
+    }
+
+    /// Make sure the items of the last array have the same type as the other items of a list
+    fn spread_items(&mut self, item_type: &mut Type) -> Result<(), Error> {
+        if self.last_type == Type::Unknown {
+            let t = if *item_type == Type::Never { Type::Any } else { item_type.clone() };
+            self.assert_type(Type::Array(Box::new(t)))?;
+        }
+
+        match self.last_type.resolved().clone() {
+            // Empty arrays don't add anything
+            Type::Array(t) if *t == Type::Never => Ok(()),
+            Type::Array(t) => {
+                self.last_type = if *t == Type::Unknown { Type::Any } else { *t };
+                self.list_item(item_type)
+            },
+            _ => Err(self.error(ErrorValue::TypeMismatch(self.last_type.clone(), Type::Array(Box::new(Type::Any))))),
+        }
+    }
+
     fn block(&mut self) -> Result<(), Error> {
         self.get_function().begin_scope();
 
         self.ignore_whitespace();
 
+        // Blocks being spread are kept in unnamed locals
+        let mut spreads = Vec::new();
+        let arguments = std::mem::replace(&mut self.arguments, false);
+
         while !self.has(TokenValue::RightBrace) {
             self.ignore_whitespace();
-            self.expression(false)?;
+            if self.take(TokenValue::Ellipsis).is_some() {
+                self.spread()?;
+                let t = self.last_type.clone();
+                self.get_function().chunk.write(op::PUSH_LOCAL);
+                spreads.push(self.get_function().add_local(String::new(), t));
+            } else {
+                self.statement(false)?;
+            }
             self.ignore_whitespace();
         }
 
         if self.take(TokenValue::RightBrace).is_none() {
             return Err(self.error(ErrorValue::UnexpectedEOF));
         }
+        self.assert_defined()?;
+        self.arguments = arguments;
 
-        // Push all locals onto the stack
+        self.make_block(&spreads);
+
+        self.get_function().end_scope();
+
+        Ok(())
+    }
+
+    /// Make a block from the locals in the current scope
+    fn make_block(&mut self, spreads: &[usize]) {
+        // Push all locals onto the stack, in the order they were added
         let mut types: Vec<(String, Type)> = Vec::new();
         let mut n = 0;
+        let mut parts = 0;
         for local in self.get_function().locals.clone() {
-            if local.depth == self.get_function().depth {
+            if local.depth != self.get_function().depth {
+                continue;
+            }
+
+            if spreads.contains(&local.index) {
+                // Finish the members before the spread so it can override them
+                if n > 0 {
+                    self.get_function().chunk.write_pair(op::MAKE_BLOCK, n);
+                    parts += 1;
+                    n = 0;
+                }
+
+                self.get_function().chunk.write_pair(op::GET_LOCAL, local.index as u16);
+                parts += 1;
+
+                if let Type::Block(members) = local.type_.resolved() {
+                    for member in members.clone() {
+                        Self::set_member(&mut types, member);
+                    }
+                }
+            } else if !local.name.is_empty() {
+                // Unnamed locals only hold values being destructured
                 n += 1;
-                types.push((local.name, local.type_.clone()));
+                let name = self.get_function().chunk.add_constant(Value::Object(Object::String(local.name.clone())));
+                self.get_function().chunk.write_pair(op::PUSH, name);
+                Self::set_member(&mut types, (local.name, local.type_.clone()));
                 self.get_function().chunk.write_pair(op::GET_LOCAL, local.index as u16);
+                if local.cell {
+                    self.get_function().chunk.write(op::READ_CELL);
+                }
             }
         }
 
         // Make the block
-        self.get_function().chunk.write_pair(op::MAKE_BLOCK, n);
+        if n > 0 || parts == 0 {
+            self.get_function().chunk.write_pair(op::MAKE_BLOCK, n);
+            parts += 1;
+        }
 
+        if parts > 1 {
+            self.get_function().chunk.write_pair(op::MERGE, parts);
+        }
 
         self.last_type = Type::Block(types);
+    }
 
-        self.get_function().end_scope();
+    /// Compile a block being spread into another
+    fn spread(&mut self) -> Result<(), Error> {
+        self.expression(true)?;
+        if self.last_type == Type::Unknown {
+            self.assert_type(Type::Block(Vec::new()))?;
+        }
 
-        Ok(())
+        match self.last_type.resolved() {
+            Type::Block(_) => Ok(()),
+            _ => Err(self.error(ErrorValue::TypeMismatch(self.last_type.clone(), Type::Block(Vec::new())))),
+        }
+    }
+
+    /// Add a member to a block type, replacing any member with the same name
+    fn set_member(members: &mut Vec<(String, Type)>, member: (String, Type)) {
+        match members.iter_mut().find(|(name, _)| *name == member.0) {
+            Some(m) => m.1 = member.1,
+            None => members.push(member),
+        }
     }
 
 }
@@ -1,18 +1,17 @@
-use std::{env, fs};
+use std::{env, path::Path, process};
 
-use vanilla::{vm::{VM, self}};
+use vanilla::vm::VM;
 
-fn main() -> Result<(), vm::Error> {
+fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() == 2 {
-        let source = fs::read_to_string(args.get(1).unwrap()).expect("Something went wrong reading the file");
-
         // Run the entry point
         let mut vm = VM::new();
-        vm.interpret(source)?;
+        if let Err(e) = vm.interpret_file(Path::new(args.get(1).unwrap())) {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
     } else {
         println!("Usage: vanilla <file>");
     }
-
-    Ok(())
 }
\ No newline at end of file
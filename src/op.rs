@@ -36,12 +36,33 @@ pub const NEGATE: u8 = 25;
 pub const NOT: u8 = 26;
 pub const JUMP: u8 = 27;
 pub const JUMP_IF_FALSE: u8 = 28;
+pub const LOOP: u8 = 29;
+pub const MAKE_CELL: u8 = 30;
+pub const READ_CELL: u8 = 31;
+pub const SET_CELL: u8 = 32;
+pub const MODULO: u8 = 33;
+pub const POWER: u8 = 34;
+pub const SLICE: u8 = 35;
+pub const NO_MATCH: u8 = 36;
+pub const MERGE: u8 = 37;
+pub const RANGE: u8 = 38;
+pub const IMPORT: u8 = 39;
+pub const JUMP_IF_PRESENT: u8 = 40;
+pub const TAG: u8 = 41;
+pub const IS_VARIANT: u8 = 42;
+pub const UNTAG: u8 = 43;
 
 pub struct Chunk {
     pub code: Vec<u8>,
     pub constants: Vec<Value>,
 }
 
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Chunk {
     pub fn new() -> Self {
         Self {
@@ -95,33 +116,30 @@ impl fmt::Debug for Chunk {
                     writeln!(f, "{:16}{:?}", "PUSH", value.clone())?;
 
                     // Print out the value if it's a function or closure
-                    match value {
-                        Value::Object(o) => {
-                            match o.borrow() {
-                                Object::Function(func) => {
-                                    let n = if func.name.len() > 0 {
-                                        format!("function \"{}\"", &func.name)
-                                    } else {
-                                        "anonymous function".to_string()
-                                    };
-                                    writeln!(f, "(entering {})", n)?;
-                                    write!(f, "{:?}", func.chunk)?;
-                                    writeln!(f, "(exiting {})", n)?;
-                                },
-                                crate::value::Object::Closure(c) => {
-                                    let n = if c.function.name.len() > 0 {
-                                        format!("{}", &c.function.name)
-                                    } else {
-                                        "anonymous closure".to_string()
-                                    };
-                                    writeln!(f, "(entering {})", n)?;
-                                    write!(f, "{:?}", c.function.chunk)?;
-                                    writeln!(f, "(exiting {})", n)?;
-                                },
-                                _ => {},
-                            }
-                        },
-                        _ => {},
+                    if let Value::Object(o) = value {
+                        match o.borrow() {
+                            Object::Function(func) => {
+                                let n = if !func.name.is_empty() {
+                                    format!("function \"{}\"", &func.name)
+                                } else {
+                                    "anonymous function".to_string()
+                                };
+                                writeln!(f, "(entering {})", n)?;
+                                write!(f, "{:?}", func.chunk)?;
+                                writeln!(f, "(exiting {})", n)?;
+                            },
+                            crate::value::Object::Closure(c) => {
+                                let n = if !c.function.name.is_empty() {
+                                    c.function.name.to_string()
+                                } else {
+                                    "anonymous closure".to_string()
+                                };
+                                writeln!(f, "(entering {})", n)?;
+                                write!(f, "{:?}", c.function.chunk)?;
+                                writeln!(f, "(exiting {})", n)?;
+                            },
+                            _ => {},
+                        }
                     }
                 },
                 PUSH_LOCAL =>  writeln!(f, "PUSH_LOCAL")?,
@@ -141,6 +159,16 @@ impl fmt::Debug for Chunk {
                 SUBTRACT => writeln!(f, "SUBTRACT")?,
                 MULTIPLY => writeln!(f, "MULTIPLY")?,
                 DIVIDE => writeln!(f, "DIVIDE")?,
+                MODULO => writeln!(f, "MODULO")?,
+                POWER => writeln!(f, "POWER")?,
+                SLICE => writeln!(f, "SLICE")?,
+                NO_MATCH => writeln!(f, "NO_MATCH")?,
+                MERGE => writeln!(f, "{:16}{}", "MERGE", self.read_u16(&mut i))?,
+                RANGE => writeln!(f, "{:16}{}", "RANGE", self.read_u16(&mut i))?,
+                IMPORT => writeln!(f, "{:16}{}", "IMPORT", self.read_u16(&mut i))?,
+                TAG => writeln!(f, "{:16}{}", "TAG", self.read_u16(&mut i))?,
+                IS_VARIANT => writeln!(f, "{:16}{}", "IS_VARIANT", self.read_u16(&mut i))?,
+                UNTAG => writeln!(f, "UNTAG")?,
                 EQUAL => writeln!(f, "EQUAL")?,
                 NOT_EQUAL => writeln!(f, "NOT_EQUAL")?,
                 GREATER_THAN => writeln!(f, "GREATER_THAN")?,
@@ -151,6 +179,11 @@ impl fmt::Debug for Chunk {
                 NOT => writeln!(f, "NOT")?,
                 JUMP => writeln!(f, "{:16}{}", "JUMP", self.read_u16(&mut i))?,
                 JUMP_IF_FALSE => writeln!(f, "{:16}{}", "JUMP_IF_FALSE", self.read_u16(&mut i))?,
+                JUMP_IF_PRESENT => writeln!(f, "{:16}{}", "JUMP_IF_PRESENT", self.read_u16(&mut i))?,
+                LOOP => writeln!(f, "{:16}{}", "LOOP", self.read_u16(&mut i))?,
+                MAKE_CELL => writeln!(f, "MAKE_CELL")?,
+                READ_CELL => writeln!(f, "READ_CELL")?,
+                SET_CELL => writeln!(f, "{:16}{}", "SET_CELL", self.read_u16(&mut i))?,
                 _ => writeln!(f, "UNKNOWN")?,
             }
         }
@@ -1,5 +1,6 @@
-use crate::token::{Token, TokenValue};
+use crate::token::{Token, TokenValue, TemplatePart};
 
+#[derive(Clone)]
 pub struct Scanner {
     source: String,
     start: usize,
@@ -17,11 +18,16 @@ impl Iterator for Scanner {
 
 impl Scanner {
     pub fn new(source: String) -> Scanner {
+        Self::starting_at(source, 1)
+    }
+
+    /// Scan source that begins partway through a file, such as an interpolated expression
+    pub fn starting_at(source: String, line: usize) -> Scanner {
         Scanner {
             source,
             start: 0,
             cursor: 0,
-            line: 1,
+            line,
         }
     }
     
@@ -55,7 +61,7 @@ impl Scanner {
     }
 
     fn lexeme(&self) -> String {
-        return self.source.chars().into_iter().skip(self.start).take(self.cursor - self.start).collect();
+        self.source.chars().skip(self.start).take(self.cursor - self.start).collect()
     }
 
     fn next_token(&mut self) -> Option<Token> {
@@ -70,18 +76,59 @@ impl Scanner {
                 ']' => Some(self.token(TokenValue::RightBracket)),
                 '{' => Some(self.token(TokenValue::LeftBrace)),
                 '}' => Some(self.token(TokenValue::RightBrace)),
-                '.' => Some(self.token(TokenValue::Dot)),
+                '.' => {
+                    if self.peek() == Some('.') && self.source.chars().nth(self.cursor + 1) == Some('.') {
+                        self.next();
+                        self.next();
+                        Some(self.token(TokenValue::Ellipsis))
+                    } else if self.peek() == Some('.') {
+                        self.next();
+                        if self.peek() == Some('=') {
+                            self.next();
+                            Some(self.token(TokenValue::DotDotEqual))
+                        } else {
+                            Some(self.token(TokenValue::DotDot))
+                        }
+                    } else {
+                        Some(self.token(TokenValue::Dot))
+                    }
+                },
                 '=' => {
                     if self.peek() == Some('=') {
                         self.next();
                         Some(self.token(TokenValue::EqualEqual))
+                    } else if self.peek() == Some('>') {
+                        self.next();
+                        Some(self.token(TokenValue::FatArrow))
                     } else {
                         Some(self.token(TokenValue::Equals))
                     }
                 },
-                '+' => Some(self.token(TokenValue::Plus)),
-                '-' => Some(self.token(TokenValue::Minus)),
-                '*' => Some(self.token(TokenValue::Star)),
+                '+' => {
+                    if self.peek() == Some('+') {
+                        self.next();
+                        Some(self.token(TokenValue::PlusPlus))
+                    } else {
+                        Some(self.token(TokenValue::Plus))
+                    }
+                },
+                '-' => {
+                    if self.peek() == Some('>') {
+                        self.next();
+                        Some(self.token(TokenValue::Arrow))
+                    } else {
+                        Some(self.token(TokenValue::Minus))
+                    }
+                },
+                '*' => {
+                    if self.peek() == Some('*') {
+                        self.next();
+                        Some(self.token(TokenValue::StarStar))
+                    } else {
+                        Some(self.token(TokenValue::Star))
+                    }
+                },
+                '%' => Some(self.token(TokenValue::Percent)),
                 '#' => {
                     self.next();
                     while let Some(c) = self.next() {
@@ -112,6 +159,14 @@ impl Scanner {
                         Some(self.token(TokenValue::GreaterThan))
                     }
                 },
+                '?' => {
+                    if self.peek() == Some('?') {
+                        self.next();
+                        Some(self.token(TokenValue::QuestionQuestion))
+                    } else {
+                        Some(self.token(TokenValue::Question))
+                    }
+                },
                 '!' => {
                     if self.peek() == Some('=') {
                         self.next();
@@ -132,8 +187,11 @@ impl Scanner {
                     if self.peek() == Some('|') {
                         self.next();
                         Some(self.token(TokenValue::Or))
+                    } else if self.peek() == Some('>') {
+                        self.next();
+                        Some(self.token(TokenValue::Pipe))
                     } else {
-                        Some(self.token(TokenValue::InvalidCharacter(c)))
+                        Some(self.token(TokenValue::Bar))
                     }
                 },
                 ':' => Some(self.token(TokenValue::Colon)),
@@ -162,7 +220,7 @@ impl Scanner {
         }
 
         // EOF
-        return None;
+        None
     }
 
     fn number(&mut self) -> Token {
@@ -174,8 +232,27 @@ impl Scanner {
             }
         }
 
+        // A fractional part makes it a float, unless this is an index like `pair.0.1`
+        let is_index = self.start > 0 && self.source.chars().nth(self.start - 1) == Some('.');
+        let has_fraction = self.peek() == Some('.') && self.source.chars().nth(self.cursor + 1).is_some_and(|c| c.is_ascii_digit());
+        if has_fraction && !is_index {
+            self.next();
+            while let Some(c) = self.peek() {
+                if c.is_ascii_digit() {
+                    self.next();
+                } else {
+                    break;
+                }
+            }
+
+            return match self.lexeme().parse::<f64>() {
+                Ok(n) => self.token(TokenValue::Float(n)),
+                Err(_e) => self.token(TokenValue::InvalidNumber(self.lexeme())),
+            };
+        }
+
         // Parse and return
-        match self.lexeme().parse::<i32>() {
+        match self.lexeme().parse::<i64>() {
             Ok(n) => self.token(TokenValue::Number(n)),
             Err(_e) => self.token(TokenValue::InvalidNumber(self.lexeme())),
         }
@@ -183,6 +260,7 @@ impl Scanner {
 
     fn string(&mut self, delim: char) -> Token {
         let mut s = String::new();
+        let mut parts = Vec::new();
         while let Some(c) = self.next() {
             if c == '\\' {
                 match self.next() {
@@ -199,7 +277,24 @@ impl Scanner {
                     },
                 }
             } else if c == delim {
-                return self.token(TokenValue::String(s));
+                if parts.is_empty() {
+                    return self.token(TokenValue::String(s));
+                }
+
+                parts.push(TemplatePart::Text(s));
+                return self.token(TokenValue::Template(parts));
+            } else if delim == '`' && c == '$' && self.peek() == Some('{') {
+                // Interpolated expression
+                self.next();
+                let line = self.line;
+                match self.embedded() {
+                    Some(e) => {
+                        parts.push(TemplatePart::Text(s));
+                        parts.push(TemplatePart::Expression(e, line));
+                        s = String::new();
+                    },
+                    None => break,
+                }
             } else if c == '\n' {
                 self.line += 1;
                 s.push('\n');
@@ -208,7 +303,43 @@ impl Scanner {
             }
         }
 
-        return self.token(TokenValue::UnterminatedString(s));
+        self.token(TokenValue::UnterminatedString(s))
+    }
+
+    /// source of an interpolated expression up to its closing brace
+    fn embedded(&mut self) -> Option<String> {
+        let mut e = String::new();
+        let mut depth = 0;
+        while let Some(c) = self.next() {
+            match c {
+                '{' => depth += 1,
+                '}' if depth == 0 => return Some(e),
+                '}' => depth -= 1,
+                '\n' => self.line += 1,
+                '"' | '`' => {
+                    // Copy nested strings as they are so their braces don't count
+                    e.push(c);
+                    while let Some(n) = self.next() {
+                        e.push(n);
+                        if n == '\\' {
+                            if let Some(escaped) = self.next() {
+                                e.push(escaped);
+                            }
+                        } else if n == '\n' {
+                            self.line += 1;
+                        } else if n == c {
+                            break;
+                        }
+                    }
+                    continue;
+                },
+                _ => {},
+            }
+
+            e.push(c);
+        }
+
+        None
     }
 
     /// identifier and keywords
@@ -224,12 +355,16 @@ impl Scanner {
 
         // Match keywords
         match lexeme.as_str() {
-            "true" => return self.token(TokenValue::Boolean(true)),
-            "false" => return self.token(TokenValue::Boolean(false)),
-            "for" => return self.token(TokenValue::For),
-            "in" => return self.token(TokenValue::In),
-            "if" => return self.token(TokenValue::If),
-            "else" => return self.token(TokenValue::Else),
+            "true" => self.token(TokenValue::Boolean(true)),
+            "false" => self.token(TokenValue::Boolean(false)),
+            "none" => self.token(TokenValue::None),
+            "for" => self.token(TokenValue::For),
+            "in" => self.token(TokenValue::In),
+            "if" => self.token(TokenValue::If),
+            "else" => self.token(TokenValue::Else),
+            "match" => self.token(TokenValue::Match),
+            "import" => self.token(TokenValue::Import),
+            "type" => self.token(TokenValue::Type),
             _ => self.token(TokenValue::Identifier(lexeme))
         }
     }
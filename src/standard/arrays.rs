@@ -1,50 +1,17 @@
 use std::borrow::Borrow;
 
-use crate::{callable::Callable, vm::{self, VM}, value::{Value, Object}, types::Type};
+use crate::{callable::Callable, vm::{self, VM, Results}, value::{Value, Object}, types::Type};
 
 use super::BuiltIn;
 
 pub struct Map;
 
-// TODO: figure out how to make this more efficient with functions and closures
 impl Callable for Map {
     fn call(&self, vm: &mut VM) -> Result<(), vm::Error> {
         let function = vm.pop()?;
         let list = vm.pop()?;
 
-        match (list.borrow(), function.borrow()) {
-            (Value::Object(Object::Array(list)), Value::Object(Object::Function(function))) => {
-                let mut new_list = Vec::new();
-                for (i, v) in list.iter().enumerate() {
-                    vm.push(v.clone())?;
-                    vm.push(Value::Number(i as i32))?;
-
-                    vm.run(function, vec![])?;
-
-                    let mapped_value = vm.pop()?;
-                    new_list.push(mapped_value);
-                }
-
-                vm.push(Value::Object(Object::Array(new_list)))?;
-            },
-            (Value::Object(Object::Array(list)), Value::Object(Object::Closure(closure))) => {
-                let mut new_list = Vec::new();
-                for (i, v) in list.iter().enumerate() {
-                    vm.push(v.clone())?;
-                    vm.push(Value::Number(i as i32))?;
-
-                    vm.run(closure.function.borrow(), closure.upvalues.clone())?;
-
-                    let mapped_value = vm.pop()?;
-                    new_list.push(mapped_value);
-                }
-
-                vm.push(Value::Object(Object::Array(new_list)))?;
-            },
-            _ => return Err(vm::Error::RuntimeError("map requires a list and a function".to_string())),
-        }
-        
-        Ok(())
+        vm.start_iteration(list, function, true, Results::Collect(Vec::new()))
     }
 }
 
@@ -53,7 +20,9 @@ impl BuiltIn for Map {
         "map"
     }
     fn get_type(&self) -> crate::types::Type {
-        Type::Function(vec![Type::Array(Box::from(Type::Any)), Type::Function(vec![Type::Any, Type::Number], Box::from(Type::Any))], Box::from(Type::Array(Box::from(Type::Any))))
+        let t = Type::Var("T".to_string());
+        let u = Type::Var("U".to_string());
+        Type::Function(vec![Type::Array(Box::from(t.clone())), Type::Function(vec![t, Type::Number], Box::from(u.clone()))], Box::from(Type::Array(Box::from(u))))
     }
 }
 
@@ -63,27 +32,7 @@ impl Callable for Loop {
         let function = vm.pop()?;
         let list = vm.pop()?;
 
-        match (list.borrow(), function.borrow()) {
-            (Value::Object(Object::Array(list)), Value::Object(Object::Function(function))) => {
-                for (i, v) in list.iter().enumerate() {
-                    vm.push(v.clone())?;
-                    vm.push(Value::Number(i as i32))?;
-
-                    vm.run(function, vec![])?;
-                }
-            },
-            (Value::Object(Object::Array(list)), Value::Object(Object::Closure(closure))) => {
-                for (i, v) in list.iter().enumerate() {
-                    vm.push(v.clone())?;
-                    vm.push(Value::Number(i as i32))?;
-
-                    vm.run(closure.function.borrow(), closure.upvalues.clone())?;
-                }
-            },
-            _ => return Err(vm::Error::RuntimeError("map requires a list and a function".to_string())),
-        }
-        
-        Ok(())
+        vm.start_iteration(list, function, true, Results::Discard)
     }
 }
 
@@ -92,7 +41,8 @@ impl BuiltIn for Loop {
         "loop"
     }
     fn get_type(&self) -> crate::types::Type {
-        Type::Function(vec![Type::Array(Box::from(Type::Any)), Type::Function(vec![Type::Any, Type::Number], Box::from(Type::Any))], Box::from(Type::Nil))
+        let t = Type::Var("T".to_string());
+        Type::Function(vec![Type::Array(Box::from(t.clone())), Type::Function(vec![t, Type::Number], Box::from(Type::Any))], Box::from(Type::Nil))
     }
 }
 
@@ -102,17 +52,18 @@ impl Callable for Length {
     fn call(&self, vm: &mut VM) -> Result<(), vm::Error> {
         let list = vm.pop()?;
 
-        match list.borrow() {
-            Value::Object(Object::Array(list)) => {
-                vm.push(Value::Number(list.len() as i32))?;
-            },
-            Value::Object(Object::String(s)) => {
-                vm.push(Value::Number(s.len() as i32))?;
-            },
-            _ => return Err(vm::Error::RuntimeError("length requires a list".to_string())),
+        let length = match list.borrow() {
+            Value::Object(Object::Array(list)) => list.len(),
+            Value::Object(Object::Range(range)) => range.len(),
+            Value::Object(Object::String(s)) => s.len(),
+            _ => return Err(vm::Error::RuntimeError("length requires a list or a string".to_string())),
+        };
+
+        // Ranges can be longer than the largest number
+        match i64::try_from(length) {
+            Ok(n) => vm.push(Value::Number(n)),
+            Err(_) => Err(vm::Error::RuntimeError(format!("length {} is too large for a number", length))),
         }
-
-        Ok(())
     } 
 }
 
@@ -121,6 +72,7 @@ impl BuiltIn for Length {
         "length"
     }
     fn get_type(&self) -> crate::types::Type {
+        // Strings have a length too, and there's no type for being either
         Type::Function(vec![Type::Any], Box::from(Type::Number))
     }
 }
\ No newline at end of file
@@ -1,4 +1,4 @@
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenValue {
     // Groups
     LeftParen,
@@ -10,13 +10,19 @@ pub enum TokenValue {
 
     // Access operator
     Dot,
+    Ellipsis,
+    DotDot,
+    DotDotEqual,
 
     // Operators
     Equals,
     Plus,
+    PlusPlus,
     Minus,
     Star,
+    StarStar,
     Slash,
+    Percent,
     Bang,
 
     // Comparison operators
@@ -28,32 +34,50 @@ pub enum TokenValue {
     BangEqual,
     And,
     Or,
+    Pipe,
+    Question,
+    QuestionQuestion,
     
     // Literals
     Identifier(String),
     String(String),
+    Template(Vec<TemplatePart>),
     UnterminatedString(String),
-    Number(i32),
+    Number(i64),
+    Float(f64),
     InvalidNumber(String),
     Boolean(bool),
+    None,
 
     // Types
     Colon,
+    Type,
+    Bar,
 
     // Control flow keywords
     If,
     Else,
     For,
     In,
+    Match,
+    Import,
+    FatArrow,
 
     // Lambda
     BackSlash,
+    Arrow,
 
     InvalidCharacter(char),
     Newline,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplatePart {
+    Text(String),
+    Expression(String, usize),
+}
+
+#[derive(Debug, Clone)]
 pub struct Token {
     pub value: TokenValue,
     pub line: usize,
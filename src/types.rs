@@ -1,34 +1,254 @@
+use std::{cell::OnceCell, fmt, rc::Rc};
+
+/// Types picked for the type variables of a generic function
+pub type Bindings = Vec<(String, Type)>;
+
+/// A type declared with a name, which it's shown as instead of what it stands for
+pub struct NamedType {
+    pub name: String,
+    // Filled in after the declaration is read, so it can refer to itself
+    type_: OnceCell<Type>,
+}
+
+impl NamedType {
+    pub fn new(name: String) -> Rc<Self> {
+        Rc::new(Self { name, type_: OnceCell::new() })
+    }
+
+    pub fn define(&self, type_: Type) {
+        let _ = self.type_.set(type_);
+    }
+
+    pub fn get(&self) -> Option<&Type> {
+        self.type_.get()
+    }
+}
+
+impl fmt::Debug for NamedType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+// Each file can declare its own types with the same names, so only the declaration itself is the same type
+impl PartialEq for NamedType {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Type {
     Number,
+    Float,
     String,
     Boolean,
     Array(Box<Type>),
     Block(Vec<(String, Type)>),
+    // A declared type whose values are one of its variants, each with a block payload
+    Union(Rc<NamedType>, Vec<(String, Type)>),
     Any,
     Unknown,
+    // A function is generic over the type variables in it
     Function(Vec<Type>, Box<Type>),
+    Var(String),
+    Optional(Box<Type>),
+    Named(Rc<NamedType>),
     Nil,
+    // The type of the items of an empty array, which can be used as anything
+    Never,
 }
 
 impl Type {
+    /// What a named type stands for
+    pub fn resolved(&self) -> &Type {
+        match self {
+            Type::Named(named) => match named.get() {
+                Some(t) => t.resolved(),
+                // Still being declared
+                None => self,
+            },
+            t => t,
+        }
+    }
+
+    /// The type made optional, if it isn't already
+    pub fn optional(self) -> Type {
+        match self {
+            Type::Optional(_) => self,
+            t => Type::Optional(Box::new(t)),
+        }
+    }
+
+    /// Whether values of this type can be compared with `==` and `!=`
+    pub fn is_comparable(&self) -> bool {
+        self.comparable(&mut Vec::new())
+    }
+
+    fn comparable(&self, seen: &mut Vec<Rc<NamedType>>) -> bool {
+        match self {
+            Type::Function(_, _) => false,
+            Type::Array(t) | Type::Optional(t) => t.comparable(seen),
+            Type::Block(members) | Type::Union(_, members) => members.iter().all(|(_, t)| t.comparable(seen)),
+            // A recursive type is comparable if the rest of it is
+            Type::Named(named) if seen.contains(named) => true,
+            Type::Named(named) => {
+                seen.push(named.clone());
+                named.get().is_none_or(|t| t.comparable(seen))
+            },
+            _ => true,
+        }
+    }
+
+    /// Whether a value of this type can be used where the constraint is expected, making it a subtype of the constraint.
+    ///
+    /// - Every type satisfies itself and `any`, and `never` satisfies every type
+    /// - `any` is only known at runtime, so it satisfies every type too. It's left out of transitivity, since a number
+    ///   satisfies `any` and `any` satisfies a string, but a number doesn't satisfy a string.
+    /// - Arrays and optionals satisfy each other when their contents do
+    /// - A value satisfies an optional of its type, but an optional doesn't satisfy a value
+    /// - A block satisfies another if it has every member of the other, and each of those satisfies the other's
+    /// - A function satisfies another with as many parameters if it accepts whatever the other does, so parameters are
+    ///   checked the other way around, and its return type satisfies the other's
+    /// - Named types satisfy what they stand for and the other way around, while unions only satisfy themselves
+    /// - A type variable only satisfies itself, except in a call where it's bound by what it's checked against
     pub fn satisfies(&self, constraint: Type) -> bool {
-        if constraint == Type::Any {
+        self.check(&constraint, &mut None, &mut Vec::new())
+    }
+
+    /// Whether this satisfies a constraint with type variables, binding each variable to the first type checked against it
+    pub fn unify(&self, constraint: &Type, bindings: &mut Bindings) -> bool {
+        self.check(constraint, &mut Some(bindings), &mut Vec::new())
+    }
+
+    /// The type of a generic function as its callers see it, where the given type variables are bound afresh at each
+    /// call. Any others belong to a function it's nested in, and stay as they are.
+    pub fn generalize(&self, names: &[String]) -> Type {
+        self.map_vars(&|name| match names.iter().any(|n| n == name) {
+            true => Type::Var(format!("?{}", name)),
+            false => Type::Var(name.to_string()),
+        })
+    }
+
+    /// The type of a generic function as its own body sees it, where its type variables stand for whatever it's called with
+    pub fn rigid(&self) -> Type {
+        self.map_vars(&|name| Type::Var(name.trim_start_matches('?').to_string()))
+    }
+
+    /// The names of the type variables in the type
+    pub fn vars(&self) -> Vec<String> {
+        match self {
+            Type::Var(name) => vec![name.clone()],
+            Type::Array(t) | Type::Optional(t) => t.vars(),
+            Type::Block(members) => members.iter().flat_map(|(_, t)| t.vars()).collect(),
+            Type::Function(params, return_type) => params.iter().chain([&**return_type]).flat_map(|t| t.vars()).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The type with its bindable type variables replaced by what they're bound to, or any if they aren't bound
+    pub fn substitute(&self, bindings: &Bindings) -> Type {
+        self.map_vars(&|name| match bindings.iter().find(|(n, _)| n == name) {
+            Some((_, t)) => t.clone(),
+            None if Self::bindable(name) => Type::Any,
+            None => Type::Var(name.to_string()),
+        })
+    }
+
+    fn map_vars(&self, f: &impl Fn(&str) -> Type) -> Type {
+        match self {
+            Type::Var(name) => f(name),
+            Type::Array(t) => Type::Array(Box::new(t.map_vars(f))),
+            Type::Optional(t) => t.map_vars(f).optional(),
+            Type::Block(members) => Type::Block(members.iter().map(|(n, t)| (n.clone(), t.map_vars(f))).collect()),
+            Type::Function(params, return_type) => Type::Function(
+                params.iter().map(|t| t.map_vars(f)).collect(),
+                Box::new(return_type.map_vars(f)),
+            ),
+            t => t.clone(),
+        }
+    }
+
+    /// Whether a type variable can be bound by a call, rather than standing for the same type throughout a function
+    fn bindable(name: &str) -> bool {
+        name.starts_with('?')
+    }
+
+    /// The members of a block that keep it from satisfying another, one line each
+    pub fn block_diff(&self, constraint: &Type) -> Vec<String> {
+        match (self.resolved(), constraint.resolved()) {
+            (Type::Block(found), Type::Block(expected)) => expected.iter()
+                .filter_map(|(name, t)| match found.iter().find(|(n, _)| n == name) {
+                    None => Some(format!("missing {}: {}", name, t)),
+                    Some((_, f)) if !f.satisfies(t.clone()) => Some(format!("{} is {}, not {}", name, f, t)),
+                    _ => None,
+                })
+                .collect(),
+            // Arrays of blocks are usually where the difference is
+            (Type::Array(found), Type::Array(expected)) => found.block_diff(expected),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Named types are unfolded as they're checked, so recursive ones keep the pairs already being checked.
+    /// Running into one of those again means it holds as far as the rest of the check goes.
+    fn check(&self, constraint: &Type, bindings: &mut Option<&mut Bindings>, assumed: &mut Vec<(Type, Type)>) -> bool {
+        if self == constraint || *constraint == Type::Any {
             return true;
         }
 
+        let bound = |name: &str, bindings: &Option<&mut Bindings>| bindings.as_ref()
+            .and_then(|b| b.iter().find(|(n, _)| n == name))
+            .map(|(_, t)| t.clone());
+
         match (self, constraint) {
-            (Type::Number, Type::Number) => true,
-            (Type::String, Type::String) => true,
-            (Type::Boolean, Type::Boolean) => true,
-            (Type::Array(t1), Type::Array(t2)) => t1.satisfies(Type::Nil) || t1.satisfies(*t2),
+            (_, Type::Var(name)) if bindings.is_some() && Self::bindable(name) => match bound(name, bindings) {
+                Some(t) if self.check(&t, bindings, assumed) => true,
+                // Something more general than what it was bound to takes its place
+                Some(t) if t.satisfies(self.clone()) => {
+                    if let Some(b) = bindings.as_mut().and_then(|b| b.iter_mut().find(|(n, _)| n == name)) {
+                        b.1 = self.clone();
+                    }
+                    true
+                },
+                Some(_) => false,
+                None => {
+                    if let Some(b) = bindings {
+                        b.push((name.clone(), self.clone()));
+                    }
+                    true
+                },
+            },
+            // Parameters of a function being passed in are checked against the variables of the one taking it
+            (Type::Var(name), _) if bindings.is_some() && Self::bindable(name) => match bound(name, bindings) {
+                Some(t) => t.check(constraint, bindings, assumed),
+                None => {
+                    if let Some(b) = bindings {
+                        b.push((name.clone(), constraint.clone()));
+                    }
+                    true
+                },
+            },
+            // After type variables, so that the items of an empty array still bind them
+            (Type::Never, _) => true,
+            (Type::Named(n1), Type::Named(n2)) if n1 == n2 => true,
+            (Type::Named(_), _) | (_, Type::Named(_)) => {
+                let pair = (self.clone(), constraint.clone());
+                if assumed.contains(&pair) {
+                    return true;
+                }
+
+                assumed.push(pair);
+                self.resolved().check(constraint.resolved(), bindings, assumed)
+            },
+            (Type::Array(t1), Type::Array(t2)) => t1.check(t2, bindings, assumed),
             (Type::Block(t1), Type::Block(t2)) => {
                 // Make sure t1 has at least all the keys in t2
                 for (k2, v2) in t2.iter() {
                     let mut has = false;
                     for (k1, v1) in t1.iter() {
                         if k1 == k2 {
-                            has = v1.satisfies(v2.clone());
+                            has = v1.check(v2, bindings, assumed);
                         }
                     }
 
@@ -39,10 +259,56 @@ impl Type {
 
                 true
             },
+            // Unions are only the same if they're the same declaration
+            (Type::Union(n1, _), Type::Union(n2, _)) => n1 == n2,
             (Type::Any, _) => true,
-            (Type::Function(t1, t2), Type::Function(t3, t4)) => t1.iter().zip(t3.iter()).all(|(a, b)| a.satisfies(b.clone())) && t2.satisfies(*t4),
-            (Type::Nil, Type::Nil) => true,
+            (Type::Function(t1, t2), Type::Function(t3, t4)) => {
+                t1.len() == t3.len()
+                    && t3.iter().zip(t1.iter()).all(|(b, a)| b.check(a, bindings, assumed))
+                    && t2.check(t4, bindings, assumed)
+            },
+            // A value can be used where it's optional, but not the other way around
+            (Type::Optional(t1), Type::Optional(t2)) => t1.check(t2, bindings, assumed),
+            (t1, Type::Optional(t2)) if *t1 != Type::Nil => t1.check(t2, bindings, assumed),
             _ => false,
         }
     }
-}
\ No newline at end of file
+}
+
+/// Types are shown the way they're written in annotations
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Type::Number => write!(f, "number"),
+            Type::Float => write!(f, "float"),
+            Type::String => write!(f, "string"),
+            Type::Boolean => write!(f, "boolean"),
+            Type::Nil => write!(f, "nil"),
+            Type::Never => write!(f, "never"),
+            Type::Any => write!(f, "any"),
+            Type::Unknown => write!(f, "unknown"),
+            Type::Array(t) => write!(f, "[{}]", t),
+            Type::Block(members) if members.is_empty() => write!(f, "{{}}"),
+            Type::Block(members) => {
+                write!(f, "{{")?;
+                for (name, t) in members {
+                    write!(f, " {}: {}", name, t)?;
+                }
+                write!(f, " }}")
+            },
+            Type::Function(params, return_type) => {
+                write!(f, "\\")?;
+                for param in params {
+                    write!(f, " {}", param)?;
+                }
+                write!(f, " = {}", return_type)
+            },
+            // A function's return type would take the question mark otherwise
+            Type::Optional(t) if matches!(**t, Type::Function(_, _)) => write!(f, "({})?", t),
+            Type::Optional(t) => write!(f, "{}?", t),
+            Type::Union(named, _) => write!(f, "{}", named.name),
+            Type::Var(name) => write!(f, "{}", name.trim_start_matches('?')),
+            Type::Named(named) => write!(f, "{}", named.name),
+        }
+    }
+}
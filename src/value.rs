@@ -1,14 +1,112 @@
-use std::{fmt, rc::Rc};
+use std::{cell::RefCell, fmt, rc::Rc};
 
-use crate::{compiler::Function, vm::Closure, standard::BuiltIn};
+use crate::{compiler::Function, vm::{self, Closure}, standard::BuiltIn};
+
+/// A range of numbers that is only materialized when it has to be
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Range {
+    pub start: i64,
+    pub end: i64,
+    pub step: i64,
+}
+
+impl Range {
+    /// Make a range, with the end being exclusive
+    pub fn new(start: i64, end: i64, step: i64) -> Result<Self, vm::Error> {
+        if step == 0 {
+            return Err(vm::Error::RuntimeError("Range step can't be 0".to_string()));
+        }
+
+        Ok(Self { start, end, step })
+    }
+
+    pub fn len(&self) -> usize {
+        let distance = if self.step > 0 {
+            self.end as i128 - self.start as i128
+        } else {
+            self.start as i128 - self.end as i128
+        };
+        let step = (self.step as i128).abs();
+
+        if distance <= 0 {
+            0
+        } else {
+            ((distance + step - 1) / step) as usize
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The nth number, worked out wide since the offset alone may not fit
+    fn nth(&self, i: usize) -> i64 {
+        (self.start as i128 + i as i128 * self.step as i128) as i64
+    }
+
+    pub fn get(&self, i: usize) -> Option<i64> {
+        if i < self.len() {
+            Some(self.nth(i))
+        } else {
+            None
+        }
+    }
+
+    /// The range without its first n numbers
+    pub fn skip(&self, n: usize) -> Self {
+        if n >= self.len() {
+            Self { start: self.end, ..*self }
+        } else {
+            Self { start: self.nth(n), ..*self }
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = i64> {
+        let range = *self;
+        (0..self.len()).map(move |i| range.nth(i))
+    }
+}
 
 pub enum Object {
     String(String),
     Array(Vec<Value>),
-    Block(Vec<Value>),
+    Range(Range),
+    Block(Vec<(String, Value)>),
+    // A variant of a union type, with its payload
+    Tagged(String, Box<Value>),
     Function(Rc<Function>),
     Closure(Rc<Closure>),
-    BuiltIn(Rc<&'static dyn BuiltIn>),
+    BuiltIn(&'static dyn BuiltIn),
+    Cell(Rc<RefCell<Option<Value>>>),
+}
+
+impl Object {
+    /// The items of an array, or the numbers of a range
+    pub fn items(&self) -> Option<Box<dyn Iterator<Item = Value> + '_>> {
+        match self {
+            Self::Array(l) => Some(Box::new(l.iter().cloned())),
+            Self::Range(r) => Some(Box::new(r.iter().map(Value::Number))),
+            _ => None,
+        }
+    }
+
+    /// How many items an array has, or numbers a range has, without going through them
+    pub fn count(&self) -> Option<usize> {
+        match self {
+            Self::Array(l) => Some(l.len()),
+            Self::Range(r) => Some(r.len()),
+            _ => None,
+        }
+    }
+
+    /// An item of an array, or a number of a range
+    pub fn get(&self, i: usize) -> Option<Value> {
+        match self {
+            Self::Array(l) => l.get(i).cloned(),
+            Self::Range(r) => r.get(i).map(Value::Number),
+            _ => None,
+        }
+    }
 }
 
 impl fmt::Debug for Object {
@@ -16,10 +114,13 @@ impl fmt::Debug for Object {
         match self {
             Self::String(s) => write!(f, "String({})", s),
             Self::Array(l) => write!(f, "Array({:?})", l),
+            Self::Range(r) => write!(f, "Range({}, {}, {})", r.start, r.end, r.step),
             Self::Block(o) => write!(f, "Block({:?})", o),
+            Self::Tagged(tag, v) => write!(f, "Tagged({}, {:?})", tag, v),
             Self::Function(func) => write!(f, "Function({})", func.name),
             Self::Closure(c) => write!(f, "Closure({})", c.function.name),
             Self::BuiltIn(_b) => write!(f, "BuiltIn"),
+            Self::Cell(_c) => write!(f, "Cell"),
         }
     }
 }
@@ -34,10 +135,18 @@ impl fmt::Display for Object {
                 }
                 Ok(())
             },
+            Self::Range(r) => {
+                for n in r.iter() {
+                    write!(f, "{}", n)?;
+                }
+                Ok(())
+            },
             Self::Block(_o) => write!(f, "(block)"),
+            Self::Tagged(tag, _v) => write!(f, "{}", tag),
             Self::Function(_f) => write!(f, "(function)"),
             Self::Closure(_c) => write!(f, "(closure)"),
             Self::BuiltIn(_b) => write!(f, "(built in)"),
+            Self::Cell(_c) => write!(f, "(cell)"),
         }
     }
 }
@@ -47,25 +156,100 @@ impl Clone for Object {
         match self {
             Self::String(s) => Self::String(s.clone()),
             Self::Array(l) => Self::Array(l.clone()),
+            Self::Range(r) => Self::Range(*r),
             Self::Block(o) => Self::Block(o.clone()),
+            Self::Tagged(tag, v) => Self::Tagged(tag.clone(), v.clone()),
             Self::Function(f) => Self::Function(f.clone()),
             Self::Closure(c) => Self::Closure(c.clone()),
-            Self::BuiltIn(b) => Self::BuiltIn(b.clone()),
+            Self::BuiltIn(b) => Self::BuiltIn(*b),
+            Self::Cell(c) => Self::Cell(c.clone()),
         }
     }
 }
 
 #[derive(Debug, Clone)]
 pub enum Value {
-    Number(i32),
+    // The absence of an optional value
+    Nil,
+    Number(i64),
+    Float(f64),
     Boolean(bool),
     Object(Object),
 }
 
+impl Value {
+    /// Structural equality. Functions can't be compared.
+    pub fn equals(&self, other: &Value) -> Result<bool, vm::Error> {
+        match (self, other) {
+            (Self::Nil, Self::Nil) => Ok(true),
+            (Self::Number(a), Self::Number(b)) => Ok(a == b),
+            (Self::Float(a), Self::Float(b)) => Ok(a == b),
+            (Self::Number(a), Self::Float(b)) | (Self::Float(b), Self::Number(a)) => Ok(*a as f64 == *b),
+            (Self::Boolean(a), Self::Boolean(b)) => Ok(a == b),
+            (Self::Object(a), Self::Object(b)) => match (a, b) {
+                (Object::String(a), Object::String(b)) => Ok(a == b),
+                // Ranges can be too long to go through, but the same length, start and step make the same numbers
+                (Object::Range(a), Object::Range(b)) => Ok(a.len() == b.len() && match a.len() {
+                    0 => true,
+                    1 => a.start == b.start,
+                    _ => a.start == b.start && a.step == b.step,
+                }),
+                (Object::Array(_) | Object::Range(_), Object::Array(_) | Object::Range(_)) => {
+                    if a.count() != b.count() {
+                        return Ok(false);
+                    }
+
+                    for (a, b) in a.items().unwrap().zip(b.items().unwrap()) {
+                        if !a.equals(&b)? {
+                            return Ok(false);
+                        }
+                    }
+
+                    Ok(true)
+                },
+                (Object::Block(a), Object::Block(b)) => {
+                    if a.len() != b.len() {
+                        return Ok(false);
+                    }
+
+                    // Members are matched by name, so their order doesn't matter
+                    for (name, a) in a.iter() {
+                        match b.iter().find(|(n, _)| n == name) {
+                            Some((_, b)) => if !a.equals(b)? {
+                                return Ok(false);
+                            },
+                            None => return Ok(false),
+                        }
+                    }
+
+                    Ok(true)
+                },
+                (Object::Tagged(t1, a), Object::Tagged(t2, b)) => Ok(t1 == t2 && a.equals(b)?),
+                (Object::Function(_) | Object::Closure(_) | Object::BuiltIn(_), _) | (_, Object::Function(_) | Object::Closure(_) | Object::BuiltIn(_)) => {
+                    Err(vm::Error::RuntimeError("Can't compare functions".to_string()))
+                },
+                _ => Ok(false),
+            },
+            _ => Ok(false),
+        }
+    }
+}
+
 impl fmt::Display for Value {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
+            Self::Nil => Ok(()),
             Self::Number(n) => write!(f, "{}", n),
+            Self::Float(n) => {
+                // Show up to 10 decimal places without trailing zeros
+                let s = format!("{:.10}", n);
+                if s.contains('.') {
+                    let s = s.trim_end_matches('0').trim_end_matches('.');
+                    write!(f, "{}", if s == "-0" { "0" } else { s })
+                } else {
+                    write!(f, "{}", s)
+                }
+            },
             Self::Boolean(b) => write!(f, "{}", b),
             Self::Object(h) => write!(f, "{}", h),
         }
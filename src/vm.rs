@@ -1,6 +1,6 @@
-use std::{rc::Rc, fmt, borrow::Borrow};
+use std::{rc::Rc, fmt, borrow::Borrow, cell::RefCell, collections::HashMap, path::Path};
 
-use crate::{op::{self}, value::{Value, Object}, compiler::{self, compile, Function}, standard::{self, BuiltIn}, callable::Callable};
+use crate::{op::{self}, value::{Value, Object, Range}, compiler::{self, compile, compile_file, Function}, standard::{self, BuiltIn}};
 
 #[derive(Debug)]
 pub enum Error {
@@ -9,49 +9,111 @@ pub enum Error {
     ValueStackUnderflow,
     ValueStackOverflow,
     InvalidStackIndex(usize),
-    IndexOutOfBounds(i32, usize),
+    IndexOutOfBounds(i64, usize),
+    IntegerOverflow(&'static str, i64, i64),
+    FrameStackOverflow(usize),
     RuntimeError(String)
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            Error::ParseError(e) => write!(f, "{:?}", e),
+            Error::ParseError(e) => write!(f, "{}", e),
             Error::FrameStackUnderflow => write!(f, "Frame stack underflow"),
             Error::ValueStackUnderflow => write!(f, "Value stack underflow"),
             Error::ValueStackOverflow => write!(f, "Value stack overflow"),
             Error::InvalidStackIndex(i) => write!(f, "Invalid stack index {}", i),
             Error::IndexOutOfBounds(i, s) => write!(f, "Index {} out of bounds for array of length {}", i, s),
+            Error::IntegerOverflow(op, a, b) => write!(f, "Integer overflow computing {} {} {}", a, op, b),
+            Error::FrameStackOverflow(n) => write!(f, "Calls nested more than {} deep", n),
             Error::RuntimeError(s) => write!(f, "Runtime error: {}", s)
         }
     }
 }
 
+/// Both operands as floats, as long as one of them is a float
+fn floats(a: &Value, b: &Value) -> Option<(f64, f64)> {
+    match (a, b) {
+        (Value::Float(a), Value::Float(b)) => Some((*a, *b)),
+        (Value::Float(a), Value::Number(b)) => Some((*a, *b as f64)),
+        (Value::Number(a), Value::Float(b)) => Some((*a as f64, *b)),
+        _ => None
+    }
+}
+
 pub struct Closure {
     pub(crate) function: Rc<Function>,
     pub upvalues: Vec<Value>
 }
 
-impl Callable for Closure {
-    fn call(&self, vm: &mut VM) -> Result<(), self::Error> {
-        vm.run(&self.function, self.upvalues.clone())
-    }
+/// The default limit on how deeply calls can be nested
+pub const MAX_DEPTH: usize = 1024;
+
+/// The limit on how many values can be on the stack at once
+const MAX_STACK: usize = 1 << 20;
+
+/// The limit on how many items an array can be made with, since a range can stand for more than fit in memory
+const MAX_ITEMS: usize = 1 << 24;
+
+/// A function being run, and where it's up to
+struct Frame {
+    function: Rc<Function>,
+    upvalues: Rc<Vec<Value>>,
+    ip: usize,
+    base: usize,
+    iteration: Option<Iteration>,
+}
+
+/// A loop that calls its body once per item, like `for` or `map`
+struct Iteration {
+    list: Object,
+    next: usize,
+    body: Value,
+    // Whether the body takes the index after the item
+    indexed: bool,
+    results: Results,
+    mark: usize,
+}
+
+/// What a loop does with the values its body leaves behind
+pub(crate) enum Results {
+    Discard,
+    Concatenate(String),
+    Collect(Vec<Value>),
 }
 
 pub struct VM {
     stack: Vec<Value>,
     locals: Vec<Value>,
-    globals: Vec<Rc<&'static dyn BuiltIn>>,
+    globals: Vec<&'static dyn BuiltIn>,
+    frames: Vec<Frame>,
+    max_depth: usize,
+    // The value of each module imported this run
+    modules: HashMap<u16, Value>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {  
-        let globals: Vec<Rc<&'static dyn BuiltIn>> = standard::get_functions().iter().map(|f| Rc::new(f.clone())).collect();         
+        Self::with_max_depth(MAX_DEPTH)
+    }
+
+    /// A VM that allows calls to be nested at most `max_depth` deep
+    pub fn with_max_depth(max_depth: usize) -> Self {
+        let globals: Vec<&'static dyn BuiltIn> = standard::get_functions().to_vec();         
 
         Self {
             stack: Vec::new(),
             locals: Vec::new(),
             globals,
+            frames: Vec::new(),
+            max_depth,
+            modules: HashMap::new(),
         }
     }
 
@@ -61,22 +123,44 @@ impl VM {
             Err(e) => return Err(Error::ParseError(e))
         };
 
+        self.execute(function)
+    }
+
+    /// Interpret a file, with its imports relative to it
+    pub fn interpret_file(&mut self, path: &Path) -> Result<(), Error> {
+        let function = match compile_file(path) {
+            Ok(c) => c,
+            Err(e) => return Err(Error::ParseError(e))
+        };
+
+        self.execute(function)
+    }
+
+    fn execute(&mut self, function: Function) -> Result<(), Error> {
         println!("Binary representation: ");
         for b in &function.chunk.code {
             println!("{:#04X} ", b);
         }
-        println!("");
+        println!();
 
         println!("Disassembled Bytecode:");
         println!("{:?}", function.chunk);
 
-        self.run(&function, Vec::new())?;
+        // Modules are evaluated once per run
+        self.modules.clear();
+
+        self.run(Rc::new(function), Vec::new())?;
 
         Ok(())
     }
 
     pub(crate) fn push(&mut self, value: Value) -> Result<(), Error> {
-        Ok(self.stack.push(value))
+        if self.stack.len() >= MAX_STACK {
+            return Err(Error::ValueStackOverflow);
+        }
+
+        self.stack.push(value);
+        Ok(())
     }
 
     pub(crate) fn pop(&mut self) -> Result<Value, Error> {
@@ -88,297 +172,1405 @@ impl VM {
 
     // TODO: try to create a macro for binary operations
     // TODO: try to create macros for incrementing the ip too
-    pub fn run(&mut self, function: &Function, upvalues: Vec<Value>) -> Result<(), Error> {  
-
-        let base = self.locals.len();
-
-        let mut ip = 0;
-        while ip < function.chunk.code.len() {
-            ip += 1;
-            match function.chunk.code[ip - 1] {
-                op::POP => {
-                    self.pop()?;
-                },
-                op::PUSH => {
-                    let i = function.chunk.read_u16(&mut ip);
-
-                    // Take a constant from the chunk's constant pool and push it onto the stack
-                    let constant = function.chunk.constants[i as usize].clone();
-                    self.push(constant)?;
-                },
-                op::GET_LOCAL => {
-                    let i = function.chunk.read_u16(&mut ip);
-                    let v = self.locals[base + i as usize].clone();
-
-                    self.push(v.clone())?;
-                },
-                op::GET_UPVALUE => {
-                    let i = function.chunk.read_u16(&mut ip);
-                    let v = upvalues[i as usize].clone();
-                    self.push(v)?;
-                },
-                op::PUSH_LOCAL => {
-                    let v = self.pop()?;
-                    self.locals.push(v);
-                },
-                op::POP_LOCAL => {
-                    self.locals.pop();
-                },
-                op::GET_GLOBAL => {
-                    let i = function.chunk.read_u16(&mut ip);
-                    let built_in = &self.globals[i as usize];
-                    let v = Value::Object(Object::BuiltIn(built_in.clone()));
-
-                    self.push(v)?;
-                },
-                op::CALL => {
-                    let arity = function.chunk.read_u16(&mut ip);
-
-                    let func = self.stack.remove(self.stack.len() - 1 - arity as usize);
-                    match func.borrow() {
-                        Value::Object(h) => {
-                            match h.borrow() {
-                               Object::BuiltIn(b) => {
-                                    b.call(self)?;
-                               },
-                               Object::Closure(c) => {
-                                    c.call(self)?;
-                               },
-                               Object::Function(f) => {
-                                    f.call(self)?;
-                               },
-                               t => {
-                                    return Err(Error::RuntimeError(format!("Cannot call {:?}", t)));
-                               }
-                            }
-                        },
-                        _ => return Err(Error::RuntimeError("Call on non-callable value".to_string()))
-                    }
-                },
-                op::MAKE_ARRAY => {
-                    let n = function.chunk.read_u16(&mut ip);
-
-                    let values: Vec<Value> = self.stack.drain(self.stack.len() - n as usize..).collect();
-                    self.push(Value::Object(Object::Array(values)))?;
-                },
-                op::MAKE_BLOCK => {
-                    let n = function.chunk.read_u16(&mut ip);
-
-                    let values: Vec<Value> = self.stack.drain(self.stack.len() - n as usize..).collect();
-                    self.push(Value::Object(Object::Block(values)))?;
-                },
-                // TODO: add a variant of this that doesn't need the stack
-                op::INDEX => {
-                    let i = self.pop()?;
-                    let target = self.pop()?;
-
-                    match (target.borrow(), i.borrow()) {
-                        (Value::Object(h), Value::Number(n)) => match h.borrow() {
-                            Object::Array(l) => {
-                                if *n < 0 || *n as usize >= l.len() {
-                                    return Err(Error::IndexOutOfBounds(*n, l.len()));
-                                }
+    /// Run a function until it returns
+    pub fn run(&mut self, function: Rc<Function>, upvalues: Vec<Value>) -> Result<(), Error> {
+        let depth = self.frames.len();
+        self.push_frame(function, Rc::new(upvalues))?;
+
+        let result = self.dispatch(depth);
+        if result.is_err() {
+            self.frames.truncate(depth);
+        }
+
+        result
+    }
+
+    fn push_frame(&mut self, function: Rc<Function>, upvalues: Rc<Vec<Value>>) -> Result<(), Error> {
+        if self.frames.len() >= self.max_depth {
+            return Err(Error::FrameStackOverflow(self.max_depth));
+        }
+
+        self.frames.push(Frame {
+            function,
+            upvalues,
+            ip: 0,
+            base: self.locals.len(),
+            iteration: None,
+        });
+
+        Ok(())
+    }
 
-                                let v = l[*n as usize].clone();
-                                self.push(v)?;
+    /// Start a call to a function or closure without waiting for it to return
+    fn push_call(&mut self, callee: &Value) -> Result<(), Error> {
+        match callee {
+            Value::Object(Object::Function(f)) => self.push_frame(f.clone(), Rc::new(Vec::new())),
+            Value::Object(Object::Closure(c)) => {
+                // Recursive functions take themselves as their first local
+                if c.function.recursive {
+                    self.push(callee.clone())?;
+                }
+
+                self.push_frame(c.function.clone(), Rc::new(c.upvalues.clone()))
+            },
+            v => Err(Error::RuntimeError(format!("Cannot call {:?}", v)))
+        }
+    }
+
+    /// Loop over an array or range in the current frame, calling the body for each item
+    pub(crate) fn start_iteration(&mut self, list: Value, body: Value, indexed: bool, results: Results) -> Result<(), Error> {
+        let list = match list {
+            Value::Object(o @ (Object::Array(_) | Object::Range(_))) => o,
+            v => return Err(Error::RuntimeError(format!("Cannot loop over {:?}", v)))
+        };
+        if !matches!(body, Value::Object(Object::Function(_) | Object::Closure(_))) {
+            return Err(Error::RuntimeError(format!("Cannot loop with non-function: {:?}", body)));
+        }
+
+        let mark = self.stack.len();
+        let frame = self.frames.last_mut().ok_or(Error::FrameStackUnderflow)?;
+        frame.iteration = Some(Iteration {
+            list,
+            next: 0,
+            body,
+            indexed,
+            results,
+            mark,
+        });
+
+        self.iterate()
+    }
+
+    /// Move a loop in the current frame on to its next item, or finish it
+    fn iterate(&mut self) -> Result<(), Error> {
+        let frame = self.frames.last_mut().ok_or(Error::FrameStackUnderflow)?;
+        let iteration = match frame.iteration.as_mut() {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        // Nil iterations leave nothing behind
+        for v in self.stack.drain(iteration.mark..) {
+            match &mut iteration.results {
+                Results::Discard => {},
+                Results::Concatenate(s) => s.push_str(&v.to_string()),
+                Results::Collect(items) => items.push(v),
+            }
+        }
+
+        match iteration.list.get(iteration.next) {
+            Some(item) => {
+                let index = iteration.next;
+                let indexed = iteration.indexed;
+                iteration.next += 1;
+                iteration.mark = self.stack.len();
+                let body = iteration.body.clone();
+
+                self.push(item)?;
+                if indexed {
+                    self.push(Value::Number(index as i64))?;
+                }
+                self.push_call(&body)
+            },
+            None => match frame.iteration.take().unwrap().results {
+                Results::Discard => Ok(()),
+                Results::Concatenate(s) => self.push(Value::Object(Object::String(s))),
+                Results::Collect(items) => self.push(Value::Object(Object::Array(items))),
+            }
+        }
+    }
+
+    /// Run frames until the frame stack is back down to the given depth
+    fn dispatch(&mut self, depth: usize) -> Result<(), Error> {
+        'frames: loop {
+            let frame = self.frames.last().ok_or(Error::FrameStackUnderflow)?;
+            let function = frame.function.clone();
+            let upvalues = frame.upvalues.clone();
+            let base = frame.base;
+            let mut ip = frame.ip;
+
+            while ip < function.chunk.code.len() {
+                ip += 1;
+                match function.chunk.code[ip - 1] {
+                    op::POP => {
+                        self.pop()?;
+                    },
+                    op::PUSH => {
+                        let i = function.chunk.read_u16(&mut ip);
+
+                        // Take a constant from the chunk's constant pool and push it onto the stack
+                        let constant = function.chunk.constants[i as usize].clone();
+                        self.push(constant)?;
+                    },
+                    op::GET_LOCAL => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let v = self.locals[base + i as usize].clone();
+
+                        self.push(v.clone())?;
+                    },
+                    op::GET_UPVALUE => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let v = upvalues[i as usize].clone();
+                        self.push(v)?;
+                    },
+                    op::PUSH_LOCAL => {
+                        let v = self.pop()?;
+                        self.locals.push(v);
+                    },
+                    op::POP_LOCAL => {
+                        self.locals.pop();
+                    },
+                    op::GET_GLOBAL => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let built_in = &self.globals[i as usize];
+                        let v = Value::Object(Object::BuiltIn(*built_in));
+
+                        self.push(v)?;
+                    },
+                    op::CALL => {
+                        let arity = function.chunk.read_u16(&mut ip);
+
+                        let func = self.stack.remove(self.stack.len() - 1 - arity as usize);
+                        match func.borrow() {
+                            Value::Object(Object::BuiltIn(b)) => {
+                                // Built ins can start loops that call back into this frame
+                                self.frames.last_mut().ok_or(Error::FrameStackUnderflow)?.ip = ip;
+                                b.call(self)?;
+                                continue 'frames;
+                            },
+                            Value::Object(Object::Function(_) | Object::Closure(_)) => {
+                                // Continue with the callee, coming back here when it returns
+                                self.frames.last_mut().ok_or(Error::FrameStackUnderflow)?.ip = ip;
+                                self.push_call(&func)?;
+                                continue 'frames;
+                            },
+                            Value::Object(t) => {
+                                return Err(Error::RuntimeError(format!("Cannot call {:?}", t)));
+                            },
+                            _ => return Err(Error::RuntimeError("Call on non-callable value".to_string()))
+                        }
+                    },
+                    op::MAKE_ARRAY => {
+                        let n = function.chunk.read_u16(&mut ip);
+
+                        let values: Vec<Value> = self.stack.drain(self.stack.len() - n as usize..).collect();
+                        self.push(Value::Object(Object::Array(values)))?;
+                    },
+                    op::MAKE_BLOCK => {
+                        let n = function.chunk.read_u16(&mut ip);
+
+                        // Each member is a name followed by its value
+                        let values: Vec<Value> = self.stack.drain(self.stack.len() - 2 * n as usize..).collect();
+                        let members = values.chunks(2).map(|m| (m[0].to_string(), m[1].clone())).collect();
+                        self.push(Value::Object(Object::Block(members)))?;
+                    },
+                    // TODO: add a variant of this that doesn't need the stack
+                    op::INDEX => {
+                        let i = self.pop()?;
+                        let target = self.pop()?;
+
+                        match (target.borrow(), i.borrow()) {
+                            (Value::Object(h), Value::Number(n)) => match h {
+                                Object::Array(l) => {
+                                    if *n < 0 || *n as usize >= l.len() {
+                                        return Err(Error::IndexOutOfBounds(*n, l.len()));
+                                    }
+
+                                    let v = l[*n as usize].clone();
+                                    self.push(v)?;
+                                },
+                                Object::Range(r) => match usize::try_from(*n).ok().and_then(|i| r.get(i)) {
+                                    Some(v) => self.push(Value::Number(v))?,
+                                    None => return Err(Error::IndexOutOfBounds(*n, r.len())),
+                                },
+                                _ => {
+                                    return Err(Error::RuntimeError(format!("Cannot index a non-indexable value: {:?}", target)))
+                                }
                             },
-                            Object::Block(o) => {
-                                let v = o[*n as usize].clone();
-                                self.push(v)?;
+                            (Value::Object(Object::Block(o)), Value::Object(Object::String(name))) => {
+                                match o.iter().find(|(n, _)| n == name) {
+                                    Some((_, v)) => {
+                                        let v = v.clone();
+                                        self.push(v)?;
+                                    },
+                                    None => return Err(Error::RuntimeError(format!("Block has no member {}", name)))
+                                }
                             },
                             _ => {
-                                return Err(Error::RuntimeError(format!("Cannot index a non-indexable value: {:?}", target)))
+                                return Err(Error::RuntimeError(format!("Cannot index a non-indexable primitive: {:?}", target)))
                             }
-                        },
-                        _ => {
-                            return Err(Error::RuntimeError(format!("Cannot index a non-indexable primitive: {:?}", target)))
-                        }
-                    };
-                },
-                op::MAKE_CLOSURE => {
-                    let func = self.pop()?;
-
-                    match func.borrow() {
-                        Value::Object(h) => match h.borrow(){
-                            Object::Function(f) => {
-                                let mut closure = Closure {
-                                    function: f.clone(),
-                                    upvalues: Vec::new()
-                                };
+                        };
+                    },
+                    op::MAKE_CLOSURE => {
+                        let func = self.pop()?;
+
+                        match func.borrow() {
+                            Value::Object(h) => match h {
+                                Object::Function(f) => {
+                                    let mut closure = Closure {
+                                        function: f.clone(),
+                                        upvalues: Vec::new()
+                                    };
     
-                                // Resolve the upvalues
-                                for upvalue in &f.upvalues {
-                                    if upvalue.is_local {
-                                        closure.upvalues.push(self.locals[base + upvalue.index as usize].clone());
-                                    } else {
-                                        closure.upvalues.push(upvalues[upvalue.index as usize].clone());
+                                    // Resolve the upvalues
+                                    for upvalue in &f.upvalues {
+                                        if upvalue.is_local {
+                                            closure.upvalues.push(self.locals[base + upvalue.index].clone());
+                                        } else {
+                                            closure.upvalues.push(upvalues[upvalue.index].clone());
+                                        }
                                     }
-                                }
     
-                                self.push(Value::Object(Object::Closure(Rc::new(closure))))?;
+                                    self.push(Value::Object(Object::Closure(Rc::new(closure))))?;
+                                },
+                                _ => {
+                                    return Err(Error::RuntimeError(format!("Cannot make closure from non-function: {:?}", func)))
+                                }
                             },
-                            _ => {
-                                return Err(Error::RuntimeError(format!("Cannot make closure from non-function: {:?}", func)))
+                            t => {
+                                return Err(Error::RuntimeError(format!("Cannot make closure from non-function: {:?}", t)))
                             }
-                        },
-                        t => {
-                            return Err(Error::RuntimeError(format!("Cannot make closure from non-function: {:?}", t)))
+                        };
+                    },
+                    op::OR => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+
+                        match (a, b) {
+                            (Value::Boolean(b1), Value::Boolean(b2)) => self.push(Value::Boolean(b1 || b2))?,
+                            _ => return Err(Error::RuntimeError("Cannot OR non-boolean values".to_string()))
                         }
-                    };
-                },
-                op::OR => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-
-                    match (a, b) {
-                        (Value::Boolean(b1), Value::Boolean(b2)) => self.push(Value::Boolean(b1 || b2))?,
-                        _ => return Err(Error::RuntimeError("Cannot OR non-boolean values".to_string()))
-                    }
-                },
-                op::AND => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-
-                    match (a, b) {
-                        (Value::Boolean(b1), Value::Boolean(b2)) => self.push(Value::Boolean(b1 && b2))?,
-                        _ => return Err(Error::RuntimeError("Cannot AND non-boolean values".to_string()))
-                    }
-                },
-                op::ADD => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Number(a + b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't add {:?} and {:?}", a, b)))
-                    };
-                },
-                op::CONCATENATE => {
-                    let n = function.chunk.read_u16(&mut ip);
-
-                    let s: String = self.stack.drain(self.stack.len() - n as usize..).map(|v| v.to_string()).collect();
-                    self.push(Value::Object(Object::String(s)))?;
-                },
-                op::SUBTRACT => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Number(a - b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't subtract {:?} and {:?}", a, b)))
-                    };
-                },
-                op::MULTIPLY => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Number(a * b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't multiply {:?} and {:?}", a, b)))
-                    };
-                },
-                op::DIVIDE => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => {
-                            if *b == 0 {
-                                return Err(Error::RuntimeError(format!("Can't divide {} by 0", a)))
+                    },
+                    op::AND => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+
+                        match (a, b) {
+                            (Value::Boolean(b1), Value::Boolean(b2)) => self.push(Value::Boolean(b1 && b2))?,
+                            _ => return Err(Error::RuntimeError("Cannot AND non-boolean values".to_string()))
+                        }
+                    },
+                    op::ADD => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => match a.checked_add(*b) {
+                                Some(n) => self.push(Value::Number(n))?,
+                                None => return Err(Error::IntegerOverflow("+", *a, *b))
+                            },
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Float(a + b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't add {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::CONCATENATE => {
+                        let n = function.chunk.read_u16(&mut ip);
+
+                        let s: String = self.stack.drain(self.stack.len() - n as usize..).map(|v| v.to_string()).collect();
+                        self.push(Value::Object(Object::String(s)))?;
+                    },
+                    op::SUBTRACT => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => match a.checked_sub(*b) {
+                                Some(n) => self.push(Value::Number(n))?,
+                                None => return Err(Error::IntegerOverflow("-", *a, *b))
+                            },
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Float(a - b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't subtract {:?} and {:?}", a, b)))
                             }
-                            self.push(Value::Number(a / b))?
-                        },
-                        _ => return Err(Error::RuntimeError(format!("Can't divide {:?} and {:?}", a, b)))
-                    };
-                },
-                op::EQUAL => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a == b))?,
-                        (Value::Boolean(a), Value::Boolean(b)) => self.push(Value::Boolean(a == b))?,
-                        (Value::Object(_h1), Value::Object(_h2)) => {
-                            todo!("comparing non-primitives");
-                        },
-                        _ => self.push(Value::Boolean(false))?
-                    };
-                },
-                op::NOT_EQUAL => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a != b))?,
-                        (Value::Boolean(a), Value::Boolean(b)) => self.push(Value::Boolean(a != b))?,
-                        (Value::Object(_h1), Value::Object(_h2)) => {
-                            todo!("comparing non-primitives");
-                        },
-                        _ => self.push(Value::Boolean(true))?
-                    };
-                },
-                op::GREATER_THAN => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a > b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
-                    };
-                },
-                op::GREATER_THAN_EQUAL => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a >= b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
-                    };
-                },
-                op::LESS_THAN => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a < b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
-                    };
-                },
-                op::LESS_THAN_EQUAL => {
-                    let b = self.pop()?;
-                    let a = self.pop()?;
-                    match (a.borrow(), b.borrow()) {
-                        (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a <= b))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
-                    };
-                },
-                op::NEGATE => {
-                    let a = self.pop()?;
-                    match a.borrow() {
-                        Value::Number(a) => self.push(Value::Number(-a))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't negate {:?}", a)))
-                    };
-                },
-                op::NOT => {
-                    let a = self.pop()?;
-                    match a.borrow() {
-                        Value::Boolean(a) => self.push(Value::Boolean(!a))?,
-                        _ => return Err(Error::RuntimeError(format!("Can't negate {:?}", a)))
-                    };
-                },
-                op::JUMP => {
-                    let offset = function.chunk.read_u16(&mut ip) - 2;
-                    ip += offset as usize;
-                },
-                op::JUMP_IF_FALSE => {
-                    let offset = function.chunk.read_u16(&mut ip) - 2;
-                    let condition = self.pop()?;
-                    match condition.borrow() {
-                        Value::Boolean(false) => {
+                        };
+                    },
+                    op::MULTIPLY => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => match a.checked_mul(*b) {
+                                Some(n) => self.push(Value::Number(n))?,
+                                None => return Err(Error::IntegerOverflow("*", *a, *b))
+                            },
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Float(a * b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't multiply {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::DIVIDE => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => {
+                                if *b == 0 {
+                                    return Err(Error::RuntimeError(format!("Can't divide {} by 0", a)))
+                                }
+                                match a.checked_div(*b) {
+                                    Some(n) => self.push(Value::Number(n))?,
+                                    None => return Err(Error::IntegerOverflow("/", *a, *b))
+                                }
+                            },
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => {
+                                    if b == 0.0 {
+                                        return Err(Error::RuntimeError(format!("Can't divide {} by 0", a)))
+                                    }
+                                    self.push(Value::Float(a / b))?
+                                },
+                                None => return Err(Error::RuntimeError(format!("Can't divide {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::MODULO => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => {
+                                if *b == 0 {
+                                    return Err(Error::RuntimeError(format!("Can't take {} modulo 0", a)))
+                                }
+                                match a.checked_rem(*b) {
+                                    Some(n) => self.push(Value::Number(n))?,
+                                    None => return Err(Error::IntegerOverflow("%", *a, *b))
+                                }
+                            },
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => {
+                                    if b == 0.0 {
+                                        return Err(Error::RuntimeError(format!("Can't take {} modulo 0", a)))
+                                    }
+                                    self.push(Value::Float(a % b))?
+                                },
+                                None => return Err(Error::RuntimeError(format!("Can't take the modulo of {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::POWER => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => {
+                                if *b < 0 {
+                                    return Err(Error::RuntimeError(format!("Can't raise {} to the negative power {}", a, b)))
+                                }
+                                match u32::try_from(*b).ok().and_then(|e| a.checked_pow(e)) {
+                                    Some(n) => self.push(Value::Number(n))?,
+                                    None => return Err(Error::IntegerOverflow("**", *a, *b))
+                                }
+                            },
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => {
+                                    if b < 0.0 {
+                                        return Err(Error::RuntimeError(format!("Can't raise {} to the negative power {}", a, b)))
+                                    }
+                                    self.push(Value::Float(a.powf(b)))?
+                                },
+                                None => return Err(Error::RuntimeError(format!("Can't raise {:?} to the power of {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::EQUAL => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        let equal = a.equals(&b)?;
+                        self.push(Value::Boolean(equal))?;
+                    },
+                    op::NOT_EQUAL => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        let equal = a.equals(&b)?;
+                        self.push(Value::Boolean(!equal))?;
+                    },
+                    op::GREATER_THAN => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a > b))?,
+                            (Value::Object(Object::String(a)), Value::Object(Object::String(b))) => self.push(Value::Boolean(a > b))?,
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Boolean(a > b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::GREATER_THAN_EQUAL => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a >= b))?,
+                            (Value::Object(Object::String(a)), Value::Object(Object::String(b))) => self.push(Value::Boolean(a >= b))?,
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Boolean(a >= b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::LESS_THAN => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a < b))?,
+                            (Value::Object(Object::String(a)), Value::Object(Object::String(b))) => self.push(Value::Boolean(a < b))?,
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Boolean(a < b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::LESS_THAN_EQUAL => {
+                        let b = self.pop()?;
+                        let a = self.pop()?;
+                        match (a.borrow(), b.borrow()) {
+                            (Value::Number(a), Value::Number(b)) => self.push(Value::Boolean(a <= b))?,
+                            (Value::Object(Object::String(a)), Value::Object(Object::String(b))) => self.push(Value::Boolean(a <= b))?,
+                            _ => match floats(&a, &b) {
+                                Some((a, b)) => self.push(Value::Boolean(a <= b))?,
+                                None => return Err(Error::RuntimeError(format!("Can't compare {:?} and {:?}", a, b)))
+                            }
+                        };
+                    },
+                    op::NEGATE => {
+                        let a = self.pop()?;
+                        match a.borrow() {
+                            Value::Number(a) => match a.checked_neg() {
+                                Some(n) => self.push(Value::Number(n))?,
+                                None => return Err(Error::IntegerOverflow("-", 0, *a))
+                            },
+                            Value::Float(a) => self.push(Value::Float(-a))?,
+                            _ => return Err(Error::RuntimeError(format!("Can't negate {:?}", a)))
+                        };
+                    },
+                    op::NOT => {
+                        let a = self.pop()?;
+                        match a.borrow() {
+                            Value::Boolean(a) => self.push(Value::Boolean(!a))?,
+                            _ => return Err(Error::RuntimeError(format!("Can't negate {:?}", a)))
+                        };
+                    },
+                    op::JUMP => {
+                        let offset = function.chunk.read_u16(&mut ip) - 2;
+                        ip += offset as usize;
+                    },
+                    op::JUMP_IF_PRESENT => {
+                        // Keep the value if there is one, otherwise go on to the default
+                        let offset = function.chunk.read_u16(&mut ip) - 2;
+                        match self.stack.last() {
+                            Some(Value::Nil) => {
+                                self.pop()?;
+                            },
+                            Some(_) => ip += offset as usize,
+                            None => return Err(Error::ValueStackUnderflow),
+                        }
+                    },
+                    op::JUMP_IF_FALSE => {
+                        let offset = function.chunk.read_u16(&mut ip) - 2;
+                        let condition = self.pop()?;
+                        if let Value::Boolean(false) = condition.borrow() {
                             ip += offset as usize;
-                        },
-                        _ => ()
+                        }
+                    },
+                    op::LOOP => {
+                        let concatenate = function.chunk.read_u16(&mut ip) == 1;
+                        let body = self.pop()?;
+                        let list = self.pop()?;
+
+                        // Each iteration is a call that comes back to this frame
+                        self.frames.last_mut().ok_or(Error::FrameStackUnderflow)?.ip = ip;
+                        let results = if concatenate { Results::Concatenate(String::new()) } else { Results::Discard };
+                        self.start_iteration(list, body, false, results)?;
+                        continue 'frames;
+                    },
+                    op::SLICE => {
+                        let start = self.pop()?;
+                        let target = self.pop()?;
+
+                        match (target.borrow(), start.borrow()) {
+                            (Value::Object(Object::Array(l)), Value::Number(n)) => {
+                                let rest = l.iter().skip(*n as usize).cloned().collect();
+                                self.push(Value::Object(Object::Array(rest)))?;
+                            },
+                            (Value::Object(Object::Range(r)), Value::Number(n)) => {
+                                self.push(Value::Object(Object::Range(r.skip(*n as usize))))?;
+                            },
+                            _ => return Err(Error::RuntimeError(format!("Cannot slice {:?}", target)))
+                        }
+                    },
+                    op::MERGE => {
+                        let n = function.chunk.read_u16(&mut ip);
+
+                        let parts: Vec<Value> = self.stack.drain(self.stack.len() - n as usize..).collect();
+
+                        let merged = if let Some(Value::Object(Object::Array(_) | Object::Range(_))) = parts.first() {
+                            // Arrays are joined end to end
+                            let count = parts.iter().map(|part| match part {
+                                Value::Object(o) => o.count().unwrap_or(0),
+                                _ => 0,
+                            }).fold(0, usize::saturating_add);
+                            if count > MAX_ITEMS {
+                                return Err(Error::RuntimeError(format!("Can't make an array of {} items", count)));
+                            }
+
+                            let mut items = Vec::with_capacity(count);
+                            for part in parts.iter() {
+                                match part {
+                                    Value::Object(o @ (Object::Array(_) | Object::Range(_))) => items.extend(o.items().unwrap()),
+                                    v => return Err(Error::RuntimeError(format!("Cannot spread {:?}", v)))
+                                }
+                            }
+
+                            Object::Array(items)
+                        } else {
+                            // Later blocks override the members of earlier ones
+                            let mut members: Vec<(String, Value)> = Vec::new();
+                            for part in parts {
+                                match part {
+                                    Value::Object(Object::Block(b)) => for (name, value) in b {
+                                        match members.iter_mut().find(|(n, _)| *n == name) {
+                                            Some(member) => member.1 = value,
+                                            None => members.push((name, value)),
+                                        }
+                                    },
+                                    v => return Err(Error::RuntimeError(format!("Cannot spread {:?}", v)))
+                                }
+                            }
+
+                            Object::Block(members)
+                        };
+
+                        self.push(Value::Object(merged))?;
+                    },
+                    op::RANGE => {
+                        let inclusive = function.chunk.read_u16(&mut ip) == 1;
+                        let step = self.pop()?;
+                        let end = self.pop()?;
+                        let start = self.pop()?;
+
+                        match (start, end, step) {
+                            (Value::Number(start), Value::Number(end), Value::Number(step)) => {
+                                // Inclusive ranges go one step further than their end
+                                let end = if inclusive {
+                                    let past = step.signum();
+                                    match end.checked_add(past) {
+                                        Some(n) => n,
+                                        None => return Err(Error::IntegerOverflow("..=", end, past))
+                                    }
+                                } else {
+                                    end
+                                };
+
+                                self.push(Value::Object(Object::Range(Range::new(start, end, step)?)))?;
+                            },
+                            _ => return Err(Error::RuntimeError("Ranges require numbers".to_string()))
+                        }
+                    },
+                    op::IMPORT => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let module = self.pop()?;
+
+                        if let Some(v) = self.modules.get(&i) {
+                            let v = v.clone();
+                            self.push(v)?;
+                        } else {
+                            match module.borrow() {
+                                Value::Object(Object::Function(f)) => self.run(f.clone(), Vec::new())?,
+                                v => return Err(Error::RuntimeError(format!("Cannot import {:?}", v)))
+                            }
+
+                            let v = self.stack.last().cloned().ok_or(Error::ValueStackUnderflow)?;
+                            self.modules.insert(i, v);
+                        }
+                    },
+                    op::TAG => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let payload = self.pop()?;
+
+                        let tag = match function.chunk.constants[i as usize].borrow() {
+                            Value::Object(Object::String(s)) => s.clone(),
+                            v => return Err(Error::RuntimeError(format!("Cannot tag with {:?}", v)))
+                        };
+
+                        self.push(Value::Object(Object::Tagged(tag, Box::new(payload))))?;
+                    },
+                    op::IS_VARIANT => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let v = self.pop()?;
+
+                        let is = match (v.borrow(), function.chunk.constants[i as usize].borrow()) {
+                            (Value::Object(Object::Tagged(tag, _)), Value::Object(Object::String(s))) => tag == s,
+                            _ => false,
+                        };
+
+                        self.push(Value::Boolean(is))?;
+                    },
+                    op::UNTAG => {
+                        let v = self.pop()?;
+                        match v {
+                            Value::Object(Object::Tagged(_, payload)) => self.push(*payload)?,
+                            v => return Err(Error::RuntimeError(format!("Cannot untag {:?}", v)))
+                        }
+                    },
+                    op::NO_MATCH => {
+                        let v = self.pop()?;
+                        return Err(Error::RuntimeError(format!("No pattern matched {:?}", v)))
+                    },
+                    op::MAKE_CELL => {
+                        self.push(Value::Object(Object::Cell(Rc::new(RefCell::new(None)))))?;
+                    },
+                    op::READ_CELL => {
+                        let cell = self.pop()?;
+                        let v = match cell.borrow() {
+                            Value::Object(Object::Cell(c)) => c.as_ref().borrow().clone(),
+                            _ => return Err(Error::RuntimeError(format!("Cannot read non-cell value: {:?}", cell)))
+                        };
+
+                        match v {
+                            Some(v) => self.push(v)?,
+                            None => return Err(Error::RuntimeError("Declared function used before its definition".to_string()))
+                        }
+                    },
+                    op::SET_CELL => {
+                        let i = function.chunk.read_u16(&mut ip);
+                        let v = self.pop()?;
+
+                        match self.locals[base + i as usize].borrow() {
+                            Value::Object(Object::Cell(c)) => {
+                                c.replace(Some(v));
+                            },
+                            t => return Err(Error::RuntimeError(format!("Cannot set non-cell value: {:?}", t)))
+                        }
+                    },
+                    o => {
+                        return Err(Error::RuntimeError(format!("Unknown opcode: {}", o)))
                     }
-                },
-                o => {
-                    return Err(Error::RuntimeError(format!("Unknown opcode: {}", o)))
-                }
-            };
+                };
+
+            }
 
+            // Return to the caller
+            self.frames.pop().ok_or(Error::FrameStackUnderflow)?;
+            if self.frames.len() <= depth {
+                return Ok(());
+            }
+
+            // The caller may be part way through a loop
+            self.iterate()?;
         }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::fs;
 
-        Ok(())
+    use super::{VM, Error};
+    use crate::{compiler::ErrorValue, types::{Type, NamedType}};
+
+    fn run(source: &str) -> VM {
+        let mut vm = VM::new();
+        vm.interpret(source.to_string()).unwrap();
+        vm
+    }
+
+    #[test]
+    fn concatenates_for_loop_iterations() {
+        let vm = run(r#"
+            names = ["Ann" "Bo"]
+            list = for name in names ("<li>" name "</li>")
+            empty = for name in [] name
+            for name in names (skipped = name)
+        "#);
 
+        let locals: Vec<String> = vm.locals.iter().map(|v| v.to_string()).collect();
+        assert_eq!(locals[1..], ["<li>Ann</li><li>Bo</li>", ""]);
+
+        // Nil iterations leave nothing behind
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn annotates_block_members_with_colons() {
+        let vm = run(r#"
+            label page: { title: string count: number } = (page.title ": " page.count)
+            result = label { title = "Posts" count = 3 }
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Posts: 3");
+    }
+
+    #[test]
+    fn renders_nested_structure_recursively() {
+        let vm = run(r#"
+            render node: { title = string children = [any] } -> string = (
+                "<li>" node.title
+                if (length node.children) > 0 ("<ul>" for child in node.children (render child) "</ul>") else ""
+                "</li>"
+            )
+            item t: string c: [any] -> { title = string children = [any] } = { title = t children = c }
+            result = render item "Home" [
+                item "Docs" [
+                    item "Intro" []
+                ]
+                item "Blog" []
+            ]
+        "#);
+
+        assert_eq!(
+            vm.locals.last().unwrap().to_string(),
+            "<li>Home<ul><li>Docs<ul><li>Intro</li></ul></li><li>Blog</li></ul></li>"
+        );
+    }
+
+    #[test]
+    fn calls_forward_declared_functions() {
+        let vm = run(r#"
+            is_odd n: number -> boolean
+            is_even n: number -> boolean = if n == 0 true else is_odd n - 1
+            is_odd n = if n == 0 false else is_even n - 1
+            result = [is_even 10 is_odd 10 is_odd 7]
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "truefalsetrue");
+    }
+
+    #[test]
+    fn defines_declared_functions_only_as_statements() {
+        let vm = run(r#"
+            result = (
+                is_odd n: number -> boolean
+                is_even n: number -> boolean = if n == 0 true else is_odd n - 1
+                is_odd n = if n == 0 false else is_even n - 1
+                is_even 4
+            )
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "true");
+
+        let result = VM::new().interpret("half n: number -> number\nx = half 4\nhalf n = n / 2".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::UsedBeforeDefinition(name) if name == "half")));
+
+        let result = VM::new().interpret("x = (\n    half n: number -> number\n    1\n)".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::MissingDefinition(name) if name == "half")));
+    }
+
+    #[test]
+    fn interpolates_template_strings() {
+        let vm = run(r#"
+            student = { name = "Carter" age = 22 }
+            result = `Hello ${student.name}, you are ${student.age + 1}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Hello Carter, you are 23");
+    }
+
+    #[test]
+    fn promotes_integers_mixed_with_floats() {
+        let vm = run(r#"
+            result = `${7 / 2} ${7 / 2.0} ${0.1 + 0.2} ${1.5 * 2} ${-0.25}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "3 3.5 0.3 3 -0.25");
+    }
+
+    #[test]
+    fn applies_modulo_and_exponent_precedence() {
+        let vm = run(r#"
+            result = `${7 % 3} ${2 * 3 ** 2} ${-2 ** 2} ${2 ** 3 ** 2}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "1 18 -4 512");
+
+        for source in ["x = 1 / 0", "x = 1 % 0", "x = 2 ** -1", "x = 1.5 / 0.0", "x = 1.5 % 0.0", "x = 2.0 ** -1.0"] {
+            let result = VM::new().interpret(source.to_string());
+            assert!(matches!(result, Err(Error::RuntimeError(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn compares_values_structurally() {
+        let vm = run(r#"
+            first = "Carter"
+            a = { name = "Carter" tags = ["a" "b"] }
+            b = { tags = ["a" "b"] name = "Carter" }
+            result = [first == "Carter" a == b [1 2] != [1 2] [[1] [2]] == [[1] [3]]]
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "truetruefalsefalse");
+    }
+
+    #[test]
+    fn orders_strings_lexicographically() {
+        let vm = run(r#"
+            result = ["apple" < "banana" "2024-01-05" >= "2024-01-10" "b" > "B" "a" <= "a"]
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "truefalsetruetrue");
+    }
+
+    #[test]
+    fn matches_array_and_block_patterns() {
+        let vm = run(r#"
+            describe xs: [number] -> string = match xs (
+                [] => "empty"
+                [x] => `one ${x}`
+                [first ...rest] => `${first} and ${length rest} more`
+            )
+            person = { name = "Carter" age = 22 }
+            greeting = match person (
+                { name: "Bob" } => "Hi Bob"
+                { name age } => `${name} is ${age}`
+            )
+            result = `${describe []}, ${describe [1]}, ${describe [1 2 3]}, ${greeting}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "empty, one 1, 1 and 2 more, Carter is 22");
+    }
+
+    #[test]
+    fn destructures_blocks_and_arrays() {
+        let vm = run(r#"
+            student = { name = "Ann" age = 20 }
+            {name age} = student
+            [first second] = [1 2]
+            grade = \ {age} bonus = age + bonus
+            ages = for {age: years} in [student student] `${years};`
+            result = `${name} ${age} ${first} ${second} ${grade student 5} ${ages}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Ann 20 1 2 25 20;20;");
+    }
+
+    #[test]
+    fn spreads_blocks_into_new_blocks() {
+        let vm = run(r#"
+            site = { title = "Site" author = "Ann" }
+            page = { ...site title = "Home" slug = "home" }
+            fallback = { title = "Draft" ...page }
+            result = `${page.title} ${page.author} ${page.slug} ${fallback.title} ${site.title}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Home Ann home Home Site");
+    }
+
+    #[test]
+    fn spreads_and_concatenates_arrays() {
+        let vm = run(r#"
+            a = [1 2]
+            b = [5]
+            spread = [0 ...a 3 4 ...b ...[]]
+            joined = a ++ b ++ [6]
+            result = `${length spread} ${spread.0} ${spread.1} ${spread.5} ${joined.3} ${length ([] ++ a)}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "6 0 1 5 6 2");
+    }
+
+    #[test]
+    fn iterates_ranges() {
+        let vm = run(r#"
+            squares = map 1..4 \ n i = n * n
+            huge = 0..9000000000000000000
+            links = for page in 1..=3 `<a>${page}</a>`
+            result = `${1..5} ${0..10 by 3} ${10..0 by -3} ${squares} ${length huge} ${huge.5} ${links} ${[0 ...1..3]}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "1234 0369 10741 149 9000000000000000000 5 <a>1</a><a>2</a><a>3</a> 012");
+
+        // A range can hold more numbers than a number can count
+        let result = VM::new().interpret("x = length (0 - 9223372036854775807)..9223372036854775807".to_string());
+        assert!(matches!(result, Err(Error::RuntimeError(_))));
+
+        // Huge ranges are compared without going through them, but can't be made into arrays
+        let vm = run(r#"
+            huge = 0..9000000000000000000
+            result = `${huge == [1]} ${huge == huge} ${huge == 0..9000000000000000000 by 1} ${huge == 1..9000000000000000001} ${0..3 == [0 1 2]} ${1..2 == 1..5 by 7}`
+        "#);
+        assert_eq!(vm.locals.last().unwrap().to_string(), "false true true false true true");
+
+        for source in ["huge = 0..9000000000000000000\nx = [0 ...huge]", "huge = 0..9000000000000000000\nx = huge ++ [1]"] {
+            let result = VM::new().interpret(source.to_string());
+            assert!(matches!(result, Err(Error::RuntimeError(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn pipes_values_into_calls() {
+        let vm = run(r#"
+            students = [{ name = "Ann" } { name = "Bob" }]
+            names = students |> map \ s: {name: string} i = s.name |> map \ n: string i = `<${n}>`
+            add a: number b: number = a + b
+            total = 1..4 |> map \ n: number i = n * 10 |> length |> add 1
+            result = `${names} ${total}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "<Ann><Bob> 4");
+    }
+
+    #[test]
+    fn runs_the_readme_pipe_example() {
+        let example = "double n: number = n * 2\nadd a: number b: number = a + b\n\n3 |> double |> add 1 |> print # prints 7\n";
+        assert!(VM::new().interpret(example.to_string()).is_ok());
+
+        let vm = run(&example.replace("3 |> double |> add 1 |> print", "result = 3 |> double |> add 1"));
+        assert_eq!(vm.locals.last().unwrap().to_string(), "7");
+    }
+
+    #[test]
+    fn imports_files_relative_to_the_importer() {
+        let dir = std::env::temp_dir().join("vanilla-imports");
+        fs::create_dir_all(dir.join("partials")).unwrap();
+        fs::write(dir.join("partials/util.van"), "shout s: string -> string = `${s}!`").unwrap();
+        fs::write(dir.join("partials/header.van"), "util = import \"util.van\"\ntitle = \"Site\"\nheader page: string -> string = `${title}: ${util.shout page}`").unwrap();
+        fs::write(dir.join("main.van"), "h = import \"partials/header.van\"\n{shout} = import \"partials/util.van\"\nresult = `${h.header \"Home\"} ${shout h.title}`").unwrap();
+        fs::write(dir.join("a.van"), "b = import \"b.van\"").unwrap();
+        fs::write(dir.join("b.van"), "a = import \"a.van\"").unwrap();
+
+        let mut vm = VM::new();
+        vm.interpret_file(&dir.join("main.van")).unwrap();
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Site: Home! Site!");
+
+        let result = VM::new().interpret_file(&dir.join("a.van"));
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::InModule(_, inner) if matches!(&inner.value, ErrorValue::ImportCycle(_)))));
+    }
+
+    #[test]
+    fn checks_modules_without_the_importers_narrowing() {
+        let dir = std::env::temp_dir().join("vanilla-narrowing");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.van"), "title page: { title: string }? -> string = page.title").unwrap();
+        fs::write(dir.join("main.van"), "label page: { title: string }? -> string = if page != none (\n    lib = import \"lib.van\"\n    lib.title page\n) else \"\"").unwrap();
+
+        // The library dereferences an optional, which is wrong wherever it's imported from
+        let result = VM::new().interpret_file(&dir.join("main.van"));
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::InModule(_, inner) if matches!(&inner.value, ErrorValue::InvalidGetTarget(_)))));
+    }
+
+    #[test]
+    fn keeps_declared_types_inside_their_files() {
+        let dir = std::env::temp_dir().join("vanilla-types");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("students.van"), "type Student = { name: string }\nshow s: Student -> string = s.name").unwrap();
+        fs::write(dir.join("main.van"), "type Student = { name: string age: number }\nlib = import \"students.van\"\nresult = lib.show { name = \"Ann\" age = 20 }").unwrap();
+        fs::write(dir.join("pages.van"), "title page: Page -> string = \"page\"").unwrap();
+        fs::write(dir.join("site.van"), "type Page = { title: string }\nlib = import \"pages.van\"").unwrap();
+
+        let mut vm = VM::new();
+        vm.interpret_file(&dir.join("main.van")).unwrap();
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Ann");
+
+        // A module can't use the types of the file importing it
+        let result = VM::new().interpret_file(&dir.join("site.van"));
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::InModule(_, inner) if matches!(&inner.value, ErrorValue::UnknownType(_)))));
+    }
+
+    #[test]
+    fn tells_apart_types_with_the_same_name() {
+        let dir = std::env::temp_dir().join("vanilla-same-names");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.van"), "type S = { name: string }\nshow s: S -> string = s.name").unwrap();
+        fs::write(dir.join("main.van"), "type S = { age: number }\nmk n: number -> S = { age = n }\nlib = import \"lib.van\"\nresult = lib.show (mk 3)").unwrap();
+
+        let result = VM::new().interpret_file(&dir.join("main.van"));
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::ArgumentTypeMismatch(_, _))));
+    }
+
+    #[test]
+    fn tells_apart_unions_with_the_same_name() {
+        let dir = std::env::temp_dir().join("vanilla-same-unions");
+        fs::create_dir_all(&dir).unwrap();
+
+        // Variants are those of the union being matched, not the one declared here with the same name
+        fs::write(dir.join("ulib.van"), "type P = | A { n: number } | B\nmk x: number -> P = A { n = x }").unwrap();
+        fs::write(dir.join("umain.van"), "type P = | A { s: string } | B\nlib = import \"ulib.van\"\nresult = match lib.mk 3 (A { s } => s\nB => \"\")").unwrap();
+        fs::write(dir.join("uread.van"), "type P = | A { s: string } | B\nlib = import \"ulib.van\"\nresult = match lib.mk 3 (A { n } => n\nB => 0)").unwrap();
+        fs::write(dir.join("upass.van"), "type P = | A { s: string } | B\nlib = import \"ulib.van\"\nshow p: P -> string = \"\"\nresult = show (lib.mk 3)").unwrap();
+
+        let result = VM::new().interpret_file(&dir.join("umain.van"));
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::InvalidGetIdentifier(_))));
+
+        let mut vm = VM::new();
+        vm.interpret_file(&dir.join("uread.van")).unwrap();
+        assert_eq!(vm.locals.last().unwrap().to_string(), "3");
+
+        let result = VM::new().interpret_file(&dir.join("upass.van"));
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::ArgumentTypeMismatch(_, _))));
+    }
+
+    #[test]
+    fn limits_how_deeply_calls_nest() {
+        let source = r#"
+            count n: number -> number = if n == 0 0 else 1 + count n - 1
+            nest n: number -> string = if n == 0 "" else (for i in [n] nest i - 1)
+            deeper n: number -> number = if n == 0 0 else (map [n] \ x: number i: number = deeper x - 1).0
+            result = `${count 50000} ${nest 50000} ${deeper 50000}`
+        "#;
+
+        let result = VM::new().interpret(source.to_string());
+        assert!(matches!(result, Err(Error::FrameStackOverflow(1024))));
+
+        // Calls don't use the native stack, so a higher limit doesn't crash
+        let mut vm = VM::with_max_depth(200000);
+        vm.interpret(source.to_string()).unwrap();
+        assert_eq!(vm.locals.last().unwrap().to_string(), "50000  0");
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn defaults_and_narrows_optionals() {
+        let vm = run(r#"
+            greet name: string? -> string = if name != none `Hi ${name}` else "Hi stranger"
+            initial name: string? -> string = if name == none "?" else name
+            label name: string? -> string = if (name != none) name else ""
+            page = { heading = "About" subtitle = none }
+            describe s: string? -> string = match s (
+                none => "missing"
+                text => "present"
+            )
+            result = `${greet "Ann"}, ${greet none}, ${initial "A"}, ${label "B"}, ${page.subtitle ?? page.heading}, ${describe none}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Hi Ann, Hi stranger, A, B, About, missing");
+
+        // Anything done with the comparison could change what it means
+        for source in [
+            "f name: string? -> string = if (name != none) == false name else \"\"",
+            "f name: string? -> string = if (name != none) or true name else \"\"",
+        ] {
+            let result = VM::new().interpret(source.to_string());
+            assert!(matches!(result, Err(Error::ParseError(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn rejects_optionals_where_values_are_expected() {
+        let mut vm = VM::new();
+        let result = vm.interpret("f name: string? -> string = name".to_string());
+
+        assert!(matches!(result, Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn matches_on_union_variants() {
+        let vm = run(r#"
+            type Page =
+                | Article { heading: string body: string }
+                | Gallery { heading: string images: [string] }
+                | Empty
+            describe page: Page -> string = match page (
+                Article { heading } => `article ${heading}`
+                Gallery g => `gallery ${g.heading} of ${length g.images}`
+                Empty => "empty"
+            )
+            pages = [
+                Article { heading = "Hello" body = "World" }
+                Gallery { heading = "Trip" images = ["a.png" "b.png"] }
+                Empty
+            ]
+            result = for page in pages (describe page ";")
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "article Hello;gallery Trip of 2;empty;");
+    }
+
+    #[test]
+    fn declares_unions_only_with_payloads_or_bars() {
+        let vm = run(r#"
+            type Wrapper = Wrapped { n: number }
+            type Unit = | Only
+            unwrap w: Wrapper -> number = match w (Wrapped { n } => n)
+            result = unwrap Wrapped { n = 4 }
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "4");
+
+        // A lone name is a type, so a typo isn't quietly taken for a variant
+        let result = VM::new().interpret("type Title = Strin".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::UnknownType(name) if name == "Strin")));
+    }
+
+    #[test]
+    fn rejects_matches_missing_a_variant() {
+        let mut vm = VM::new();
+        let result = vm.interpret(r#"
+            type Page = Article { heading: string } | Empty
+            describe page: Page -> string = match page (
+                Article { heading: "Home" } => "home"
+                Empty => "empty"
+            )
+        "#.to_string());
+
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::NonExhaustiveMatch(_))));
+    }
+
+    #[test]
+    fn keeps_types_through_generic_functions() {
+        let vm = run(r#"
+            first <T> list: [T] -> T = list.0
+            pair <A B> a: A b: B -> { left: A right: B } = { left = a right = b }
+            students = [{ name = "Ann" age = 20 } { name = "Bo" age = 22 }]
+            ranked = map students \ s: { name: string age: number } i: number = { name = s.name rank = i + 1 }
+            p = pair "Cy" 3
+            result = `${(first ranked).name} ${ranked.1.rank} ${p.left} ${p.right + 1} ${length ranked}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Ann 2 Cy 4 2");
+
+        // A type variable is only bound once per call, and stays opaque in the body
+        for source in ["first <T> a: T b: T -> T = a\nx = first 1 \"a\"", "f <T> x: T -> T = 5", "x = (map [1 2] \\ n: number i: number = n).0 + \"a\""] {
+            let result = VM::new().interpret(source.to_string());
+            assert!(matches!(result, Err(Error::ParseError(_))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn binds_type_variables_per_call() {
+        let vm = run(r#"
+            pick <T U> a: T b: U -> T = a
+            flip <T U> a: T b: U -> U = pick b a
+            wrap <T> x: T -> [T] = [x]
+            nest <T> x: T -> [[T]] = wrap (wrap x)
+            result = `${flip 1 "b"} ${(nest 2).0.0 + 1}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "b 3");
+
+        // The variables of the function being called are kept apart from those of the caller
+        let result = VM::new().interpret("pick <T U> a: T b: U -> T = a
+flip <T U> a: T b: U -> T = pick b a".to_string());
+        assert!(matches!(result, Err(Error::ParseError(_))));
+    }
+
+    #[test]
+    fn passes_functions_to_generic_functions() {
+        let vm = run(r#"
+            twice <T> g: \ T = T x: T -> T = g (g x)
+            quad <T> g: \ T = T x: T -> T = twice g (twice g x)
+            shout s: string -> string = `${s}!`
+            count <T> items: [T] -> number = (length [1 2]) + length items
+            result = `${twice \ y: number = y + 1 3} ${quad \ y: number = y * 2 1} ${twice shout "hi"} ${count ["a"]}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "5 16 hi!! 3");
+
+        // Inside a generic function, its own type variables can't be bound to anything else
+        let result = VM::new().interpret("bad <T> g: \\ T = T x: T -> T = g 1".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::ArgumentTypeMismatch(_, _))));
+    }
+
+    #[test]
+    fn infers_lambda_parameters_from_calls() {
+        let vm = run(r#"
+            students = [{ name = "Ann" age = 20 } { name = "Bo" age = 22 }]
+            names = map students \ s i = s.name
+            ages = map students \ {age} i = age + i
+            result = `${names.1} ${ages.1} ${(map [1 2 3] \ n i = n * 2).2}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "Bo 23 6");
+
+        let result = VM::new().interpret("x = map [1 2] \\ n i = n.name".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::InvalidGetTarget(_))));
+    }
+
+    #[test]
+    fn checks_against_type_aliases() {
+        let vm = run(r#"
+            type Student = { name: string age: number }
+            type Roster = [Student]
+            older s: Student -> Student = { name = s.name age = s.age + 1 }
+            names roster: Roster -> [string] = map roster \ s i = s.name
+            students = [{ name = "Ann" age = 20 } older { name = "Bo" age = 21 }]
+            result = `${(older students.0).age} ${(names students).1}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "21 Bo");
+
+        // Errors show the alias rather than everything it stands for
+        let result = VM::new().interpret(r#"
+            type Student = { name: string age: number }
+            f s: Student -> number = s.age
+            x = f { name = "Ann" }
+        "#.to_string());
+        match result {
+            Err(Error::ParseError(e)) => assert_eq!(format!("{:?}", e.value), r#"ArgumentTypeMismatch(Named(Student), Block([("name", String)]))"#),
+            _ => panic!("expected a type error"),
+        }
+    }
+
+    #[test]
+    fn checks_trees_against_recursive_types() {
+        let vm = run(r#"
+            type Item = { title: string children: [Item] }
+            render node: Item -> string = (
+                "<li>" node.title
+                if (length node.children) > 0 ("<ul>" for child in node.children (render child) "</ul>") else ""
+                "</li>"
+            )
+            item t: string c: [Item] -> Item = { title = t children = c }
+            menu = item "Home" [item "Docs" [item "Intro" []] item "Blog" []]
+            literal = render { children = [{ children = [{ children = [] title = "C" }] title = "B" }] title = "A" }
+            result = `${render menu} ${literal}`
+        "#);
+
+        assert_eq!(
+            vm.locals.last().unwrap().to_string(),
+            "<li>Home<ul><li>Docs<ul><li>Intro</li></ul></li><li>Blog</li></ul></li> <li>A<ul><li>B<ul><li>C</li></ul></li></ul></li>"
+        );
+
+        let vm = run(r#"
+            type Tree = Leaf | Node { value: number left: Tree right: Tree }
+            sum tree: Tree -> number = match tree (
+                Leaf => 0
+                Node { value left right } => value + (sum left) + (sum right)
+            )
+            node v: number l: Tree r: Tree -> Tree = Node { value = v left = l right = r }
+            result = sum node 1 (node 2 Leaf Leaf) (node 3 Leaf (node 4 Leaf Leaf))
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "10");
+
+        // Types that refer to themselves are checked against each other without unfolding forever
+        let vm = run(r#"
+            type A = { next: [A] }
+            type B = { next: [B] }
+            count b: B -> number = length b.next
+            forward a: A -> number = count a
+            result = forward { next = [{ next = [] }] }
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "1");
+
+        for source in [
+            "type Item = { title: string children: [Item] }\nf i: Item -> string = i.title\nx = f { children = [{ children = [] title = 3 }] title = \"A\" }",
+            "type A = { next: [A] }\ntype C = { next: [number] }\nf c: C -> number = 1\ng a: A -> number = f a",
+        ] {
+            let result = VM::new().interpret(source.to_string());
+            assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::ArgumentTypeMismatch(_, _))), "{}", source);
+        }
+    }
+
+    #[test]
+    fn shows_types_the_way_they_are_written() {
+        let map = Type::Function(
+            vec![Type::Array(Box::new(Type::Any)), Type::Function(vec![Type::Any, Type::Number], Box::new(Type::Any))],
+            Box::new(Type::Array(Box::new(Type::Any))),
+        );
+        assert_eq!(map.to_string(), "\\ [any] \\ any number = any = [any]");
+        assert_eq!(Type::Block(vec![("name".to_string(), Type::String), ("tags".to_string(), Type::Array(Box::new(Type::String)).optional())]).to_string(), "{ name: string tags: [string]? }");
+
+        let result = VM::new().interpret(r#"
+            type Student = { name: string age: number }
+            f s: Student -> number = s.age
+            x = f { name = 3 }
+        "#.to_string());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Line 4: Argument type mismatch: expected Student, found { name: number }\n    name is number, not string\n    missing age: number"
+        );
+
+        let result = VM::new().interpret("x = map [1 2] \\ n: string i: number = n".to_string());
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Line 1: Argument type mismatch: expected \\ number number = any, found \\ string number = string"
+        );
+    }
+
+    #[test]
+    fn satisfies_is_a_subtyping_relation() {
+        let name = NamedType::new("Name".to_string());
+        name.define(Type::String);
+        let person = Type::Block(vec![("name".to_string(), Type::String)]);
+        let student = Type::Block(vec![("name".to_string(), Type::String), ("age".to_string(), Type::Number)]);
+        let array = |t: &Type| Type::Array(Box::new(t.clone()));
+        let function = |param: &Type, result: Type| Type::Function(vec![param.clone()], Box::new(result));
+
+        // Any is left out, since it fits both ways
+        let types = vec![
+            Type::Number, Type::Float, Type::String, Type::Boolean, Type::Nil, Type::Never,
+            array(&Type::Number), array(&Type::Never), array(&person), array(&student),
+            person.clone(), student.clone(), Type::Named(name), Type::Var("T".to_string()),
+            Type::String.optional(), Type::Never.optional(), student.clone().optional(),
+            function(&person, Type::Number), function(&student, Type::Number), function(&person, Type::Never),
+            function(&student, Type::Number.optional()), Type::Union(NamedType::new("Page".to_string()), vec![("Empty".to_string(), Type::Block(Vec::new()))]),
+        ];
+
+        for a in types.iter() {
+            assert!(a.satisfies(a.clone()), "{} satisfies itself", a);
+            assert!(Type::Never.satisfies(a.clone()), "never satisfies {}", a);
+            assert!(a.satisfies(Type::Any), "{} satisfies any", a);
+        }
+
+        for a in types.iter() {
+            for b in types.iter() {
+                for c in types.iter() {
+                    if a.satisfies(b.clone()) && b.satisfies(c.clone()) {
+                        assert!(a.satisfies(c.clone()), "{} satisfies {} through {}", a, c, b);
+                    }
+                }
+            }
+        }
+
+        // Parameters go the other way
+        assert!(function(&person, Type::Number).satisfies(function(&student, Type::Number)));
+        assert!(!function(&student, Type::Number).satisfies(function(&person, Type::Number)));
+        assert!(function(&person, Type::Never).satisfies(function(&student, Type::Number)));
+        assert!(!Type::Function(vec![], Box::new(Type::Number)).satisfies(function(&person, Type::Number)));
+
+        assert!(array(&Type::Never).satisfies(array(&Type::String)));
+        assert!(!array(&Type::String).satisfies(array(&Type::Never)));
+        assert!(!Type::String.optional().satisfies(Type::String));
+    }
+
+    #[test]
+    fn types_empty_arrays_and_callbacks() {
+        let vm = run(r#"
+            count xs: [string] -> number = length xs
+            nested = [[] [1 2] []]
+            students = [{ name = "Ann" age = 20 }]
+            names = map students \ s: { name: string } i: number = s.name
+            result = `${count []} ${length nested.1} ${length ([] ++ [3])} ${names.0}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "0 2 1 Ann");
+
+        let result = VM::new().interpret("x = map [{ name = \"Ann\" }] \\ s: { name: string age: number } i: number = s.name".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(e.value, ErrorValue::ArgumentTypeMismatch(_, _))));
+    }
+
+    #[test]
+    fn gets_lengths_of_strings_and_arrays() {
+        let vm = run(r#"
+            name = "Ann"
+            result = `${length "abc"} ${length name} ${length [1 2]} ${length 0..5}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "3 3 2 5");
+
+        let result = VM::new().interpret("x = length 3".to_string());
+        assert!(matches!(result, Err(Error::RuntimeError(_))));
+    }
+
+    #[test]
+    fn gets_members_of_never() {
+        let vm = run(r#"
+            titles = for page in [] page.title
+            names = map [] \ s i = s.name
+            firsts = (map [] \ s i = s.0) ++ [1]
+            result = `${titles}|${length names}|${firsts}`
+        "#);
+
+        assert_eq!(vm.locals.last().unwrap().to_string(), "|0|1");
+    }
+
+    #[test]
+    fn reports_integer_overflow() {
+        let mut vm = VM::new();
+        let result = vm.interpret("x = 9223372036854775807 * 2".to_string());
+
+        assert!(matches!(result, Err(Error::IntegerOverflow("*", 9223372036854775807, 2))));
+
+        let result = VM::new().interpret("x = (-9223372036854775807 - 1) / -1".to_string());
+        assert!(matches!(result, Err(Error::IntegerOverflow("/", i64::MIN, -1))));
+
+        let result = VM::new().interpret("x = 99999999999999999999".to_string());
+        assert!(matches!(result, Err(Error::ParseError(e)) if matches!(&e.value, ErrorValue::InvalidNumber(s) if s == "99999999999999999999")));
+    }
+}